@@ -0,0 +1,219 @@
+//! archive.rs
+//!
+//! Keeps a running history of every Activity we've ever seen. The APIs we scrape
+//! only ever hand back the most recent stuff, so anything that wants to look
+//! further back (e.g, the heatmap) reads from here instead.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::error::Error;
+use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serializer};
+use chrono::NaiveDateTime;
+
+use crate::Activity;
+use crate::state::load_json;
+
+const FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+fn serialize_archive_timestamp<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    serializer.serialize_str(&dt.format(FORMAT).to_string())
+}
+
+fn deserialize_archive_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    NaiveDateTime::parse_from_str(&s, FORMAT).map_err(serde::de::Error::custom)
+}
+
+/// An Activity as it's stored in `activity-archive.json`. Unlike the feed, timestamps
+/// are stored as-is (ISO 8601, UTC) rather than humanized.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchivedActivity {
+    #[serde(rename = "type")]
+    pub activity_type: String,
+
+    /// A stable identity for the entry (see `Activity::id`), if its source has one.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+
+    /// For a group, the IDs of everything that's ever been folded into it (see
+    /// `Activity::members`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+
+    pub content: String,
+    pub url: String,
+    pub action: String,
+
+    #[serde(serialize_with = "serialize_archive_timestamp", deserialize_with = "deserialize_archive_timestamp")]
+    pub ts: NaiveDateTime
+}
+
+impl ArchivedActivity {
+    /// What makes an entry unique: its ID, or failing that its permalink. Neither the
+    /// content nor the timestamp can be part of this, as both change when an entry grows
+    /// (e.g, a thread gets another tweet, or another push joins a burst).
+    fn key(&self) -> (String, String) {
+        match self.id.as_ref() {
+            "" => (self.activity_type.clone(), self.url.clone()),
+            id => (self.activity_type.clone(), id.to_string())
+        }
+    }
+
+    fn member_keys(&self) -> Vec<(String, String)> {
+        self.members.iter().map(|id| (self.activity_type.clone(), id.clone())).collect()
+    }
+}
+
+impl From<&Activity> for ArchivedActivity {
+    fn from(activity: &Activity) -> Self {
+        ArchivedActivity {
+            activity_type: activity.activity_type.clone(),
+            id: activity.id.clone().unwrap_or_default(),
+            members: activity.members.clone(),
+            content: activity.content.clone(),
+            url: activity.datetime.url.clone(),
+            action: activity.datetime.action.clone(),
+            ts: activity.datetime.ts
+        }
+    }
+}
+
+fn archive_path(path: &str) -> String {
+    format!("{}/activity-archive.json", path)
+}
+
+/// Loads the archive from `path`. A missing archive is just an empty one.
+pub fn load(path: &str) -> Result<Vec<ArchivedActivity>, Box<Error>> {
+    load_json(&archive_path(path))
+}
+
+pub fn save(path: &str, archive: &[ArchivedActivity]) -> Result<(), Box<Error>> {
+    let contents = serde_json::to_string(archive)?;
+    std::fs::write(archive_path(path), contents)?;
+    Ok(())
+}
+
+/// Folds `activities` into `archive`. Anything already in there gets replaced with the
+/// newer version, rather than added again. The result is kept newest first.
+///
+/// A group is also recognized by any event it's had folded into it, so it keeps its ID
+/// after its first event drops off what the source hands back. A lone event that's
+/// already part of an archived group is left out.
+pub fn merge<I>(archive: &mut Vec<ArchivedActivity>, activities: I) where I: IntoIterator<Item = ArchivedActivity> {
+    let mut seen: HashMap<(String, String), usize> = HashMap::new();
+    for (i, a) in archive.iter().enumerate() {
+        for key in a.member_keys().into_iter().chain(std::iter::once(a.key())) {
+            seen.insert(key, i);
+        }
+    }
+
+    for activity in activities {
+        let found = seen.get(&activity.key()).or_else(|| {
+            activity.member_keys().iter().find_map(|key| seen.get(key))
+        }).cloned();
+
+        let i = match found {
+            Some(i) if activity.members.is_empty() && !archive[i].members.is_empty() => continue,
+
+            Some(i) => {
+                let previous = std::mem::replace(&mut archive[i], activity);
+                archive[i].id = previous.id;
+                for member in previous.members {
+                    if !archive[i].members.contains(&member) {
+                        archive[i].members.push(member);
+                    }
+                }
+
+                i
+            },
+
+            None => {
+                archive.push(activity);
+                archive.len() - 1
+            }
+        };
+
+        for key in archive[i].member_keys().into_iter().chain(std::iter::once(archive[i].key())) {
+            seen.insert(key, i);
+        }
+    }
+
+    archive.sort_by_key(|activity| std::cmp::Reverse(activity.ts));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, url: &str, content: &str, ts: &str) -> ArchivedActivity {
+        ArchivedActivity {
+            activity_type: "github".into(),
+            id: id.into(),
+            members: vec![],
+            content: content.into(),
+            url: url.into(),
+            action: "On".into(),
+            ts: NaiveDateTime::parse_from_str(ts, FORMAT).unwrap()
+        }
+    }
+
+    #[test]
+    fn replaces_entries_that_changed() {
+        let mut archive = vec![entry("events/1", "", "Pushed 1 commit", "2019-01-01T10:00:00Z")];
+        merge(&mut archive, vec![entry("events/1", "", "Pushed 3 commits in 2 pushes", "2019-01-01T10:30:00Z")]);
+
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].content, "Pushed 3 commits in 2 pushes");
+        assert_eq!(archive[0].ts, NaiveDateTime::parse_from_str("2019-01-01T10:30:00Z", FORMAT).unwrap());
+    }
+
+    #[test]
+    fn keys_on_the_permalink_without_an_id() {
+        let mut archive = vec![entry("", "https://twitter.com/ryanmcgrath/status/1", "One", "2019-01-01T10:00:00Z")];
+        merge(&mut archive, vec![
+            entry("", "https://twitter.com/ryanmcgrath/status/1", "One\n\nTwo", "2019-01-01T10:00:00Z"),
+            entry("", "https://twitter.com/ryanmcgrath/status/3", "Three", "2019-01-02T10:00:00Z")
+        ]);
+
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive[0].content, "Three");
+        assert_eq!(archive[1].content, "One\n\nTwo");
+    }
+
+    fn group(id: &str, members: &[&str], content: &str, ts: &str) -> ArchivedActivity {
+        ArchivedActivity {
+            members: members.iter().map(|member| member.to_string()).collect(),
+            ..entry(id, "", content, ts)
+        }
+    }
+
+    #[test]
+    fn keeps_a_group_together_after_its_first_event_drops_off() {
+        let mut archive = vec![group("events/1", &["events/1", "events/2"], "Pushed 2 commits in 2 pushes", "2019-01-01T10:30:00Z")];
+
+        // events/1 fell off the page, and events/3 came in.
+        merge(&mut archive, vec![group("events/2", &["events/2", "events/3"], "Pushed 4 commits in 2 pushes", "2019-01-01T11:00:00Z")]);
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].id, "events/1");
+        assert_eq!(archive[0].content, "Pushed 4 commits in 2 pushes");
+        assert_eq!(archive[0].members, vec!["events/2", "events/3", "events/1"]);
+
+        // And events/2 after it, leaving a push that's in the group on its own.
+        merge(&mut archive, vec![entry("events/3", "", "Pushed 2 commits", "2019-01-01T11:00:00Z")]);
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].id, "events/1");
+        assert_eq!(archive[0].content, "Pushed 4 commits in 2 pushes");
+    }
+
+    #[test]
+    fn reads_entries_without_an_id() {
+        let archive: Vec<ArchivedActivity> = serde_json::from_str(
+            r#"[{"type": "github", "content": "Hi", "url": "", "action": "On", "ts": "2019-01-01T10:00:00Z"}]"#
+        ).unwrap();
+
+        assert_eq!(archive[0].id, "");
+        assert!(!serde_json::to_string(&archive).unwrap().contains("\"id\""));
+    }
+}
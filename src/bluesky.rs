@@ -0,0 +1,393 @@
+//! bluesky.rs
+//!
+//! Fetches posts for a handle on Bluesky, via `app.bsky.feed.getAuthorFeed`. Links,
+//! mentions and tags come back as "facets" over the post text, which is the same
+//! problem as Twitter's entity indices - except facets count UTF-8 bytes, so they
+//! get converted to characters first and then go through the same splicing.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use serde::{Deserializer, Deserialize};
+use serde_json::Value;
+use chrono::NaiveDateTime;
+
+use crate::{Activity, Attachment, DateTime, escape};
+use crate::entities::{Span, splice};
+
+#[derive(Deserialize, Debug)]
+pub struct Author {
+    pub did: String,
+    pub handle: String
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ByteSlice {
+    pub byte_start: usize,
+    pub byte_end: usize
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Facet {
+    pub index: ByteSlice,
+    pub features: Vec<Value>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub text: String,
+
+    #[serde(default)]
+    pub facets: Vec<Facet>,
+
+    #[serde(deserialize_with = "deserialize_bluesky_timestamp")]
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Post {
+    pub uri: String,
+    pub author: Author,
+    pub record: Record,
+    pub embed: Option<Value>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Reason {
+    #[serde(rename = "$type")]
+    pub reason_type: String,
+
+    #[serde(deserialize_with = "deserialize_bluesky_timestamp")]
+    pub indexed_at: NaiveDateTime
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FeedItem {
+    pub post: Post,
+    pub reason: Option<Reason>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthorFeed {
+    pub feed: Vec<FeedItem>
+}
+
+fn deserialize_bluesky_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    chrono::DateTime::parse_from_rfc3339(&s).map(|dt| dt.naive_utc()).map_err(serde::de::Error::custom)
+}
+
+/// Only runs if there's a handle configured.
+pub fn enabled() -> bool {
+    var("RYMC_BLUESKY_HANDLE").map(|v| !v.is_empty()).unwrap_or(false)
+}
+
+/// `at://<did>/app.bsky.feed.post/<rkey>` -> the post's page on bsky.app.
+fn permalink(uri: &str, handle: &str) -> String {
+    let rkey = uri.rsplit('/').next().unwrap_or_default();
+    format!("https://bsky.app/profile/{}/post/{}", handle, rkey)
+}
+
+fn profile_link(handle: &str) -> String {
+    format!(
+        "[@{}](https://bsky.app/profile/{} \"View @{} on Bluesky\")",
+        escape::markdown_text(handle), handle, escape::markdown_link_title(handle)
+    )
+}
+
+/// Facets index by byte; spans index by character. Anything that doesn't land on a
+/// character boundary is broken, and gets skipped.
+fn char_index(text: &str, byte: usize) -> Option<u32> {
+    match byte == text.len() {
+        true => Some(text.chars().count() as u32),
+        false => text.char_indices().position(|(i, _)| i == byte).map(|i| i as u32)
+    }
+}
+
+/// The post text with its facets linked up. Facets come from whichever client wrote
+/// the post, so empty, backwards, out of range or overlapping ones get skipped.
+fn render_text(text: &str, facets: &[Facet]) -> String {
+    let mut spans: Vec<Span> = vec![];
+
+    let mut facets: Vec<&Facet> = facets.iter().filter(|facet| {
+        facet.index.byte_start < facet.index.byte_end && facet.index.byte_end <= text.len()
+    }).collect();
+    facets.sort_by_key(|facet| facet.index.byte_start);
+
+    let mut previous_end = 0;
+    for facet in facets {
+        if facet.index.byte_start < previous_end {
+            continue;
+        }
+
+        let (start, end) = match (char_index(text, facet.index.byte_start), char_index(text, facet.index.byte_end)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => continue
+        };
+
+        let feature = match facet.features.first() {
+            Some(feature) => feature,
+            None => continue
+        };
+
+        let label = &text[facet.index.byte_start..facet.index.byte_end];
+        let replacement = match feature["$type"].as_str().unwrap_or_default() {
+            "app.bsky.richtext.facet#link" => format!(
+                "[{}]({})",
+                escape::markdown_text(label), escape::markdown_url(feature["uri"].as_str().unwrap_or_default())
+            ),
+
+            "app.bsky.richtext.facet#mention" => format!(
+                "[{}](https://bsky.app/profile/{} \"View {} on Bluesky\")",
+                escape::markdown_text(label), feature["did"].as_str().unwrap_or_default(), escape::markdown_link_title(label)
+            ),
+
+            "app.bsky.richtext.facet#tag" => {
+                let tag = feature["tag"].as_str().unwrap_or_default();
+                format!(
+                    "[{}](https://bsky.app/hashtag/{} \"View posts tagged #{} on Bluesky\")",
+                    escape::markdown_text(label), escape::markdown_url(tag), escape::markdown_link_title(tag)
+                )
+            },
+
+            _ => continue
+        };
+
+        previous_end = facet.index.byte_end;
+        spans.push(Span::new([start, end], replacement));
+    }
+
+    splice(text, spans, None, escape::markdown_text)
+}
+
+/// Images (and video) from an embed view, for the post at `url`. `recordWithMedia` keeps
+/// its media one level down.
+fn embed_media(embed: &Value, url: &str) -> Vec<Attachment> {
+    match embed["$type"].as_str().unwrap_or_default() {
+        "app.bsky.embed.images#view" => embed["images"].as_array().map(|images| images.iter().filter_map(|image| {
+            Some(Attachment {
+                kind: "image".into(),
+                url: image["fullsize"].as_str()?.to_string(),
+                preview_url: None,
+                alt_text: image["alt"].as_str().filter(|alt| !alt.is_empty()).map(|alt| alt.to_string())
+            })
+        }).collect()).unwrap_or_default(),
+
+        // The video itself is an HLS playlist, which nothing but a player can do anything
+        // with; the thumbnail links to the post instead, where it plays.
+        "app.bsky.embed.video#view" => match embed["thumbnail"].as_str() {
+            Some(thumbnail) => vec![Attachment {
+                kind: "video".into(),
+                url: url.to_string(),
+                preview_url: Some(thumbnail.to_string()),
+                alt_text: embed["alt"].as_str().map(|alt| alt.to_string())
+            }],
+
+            None => vec![]
+        },
+
+        "app.bsky.embed.recordWithMedia#view" => embed_media(&embed["media"], url),
+        _ => vec![]
+    }
+}
+
+/// The quoted post, if the embed is a quote.
+fn embed_quote(embed: &Value) -> Option<&Value> {
+    let record = match embed["$type"].as_str()? {
+        "app.bsky.embed.record#view" => &embed["record"],
+        "app.bsky.embed.recordWithMedia#view" => &embed["record"]["record"],
+        _ => return None
+    };
+
+    match record["$type"].as_str()? {
+        "app.bsky.embed.record#viewRecord" => Some(record),
+        _ => None
+    }
+}
+
+/// Link cards (`external`) render as a plain link to whatever was shared.
+fn embed_link(embed: &Value) -> Option<String> {
+    match embed["$type"].as_str()? {
+        "app.bsky.embed.external#view" => {
+            let uri = embed["external"]["uri"].as_str()?;
+            let title = match embed["external"]["title"].as_str() {
+                Some(title) if !title.is_empty() => title,
+                _ => uri
+            };
+
+            Some(format!("[{}]({})", escape::markdown_text(title), escape::markdown_url(uri)))
+        },
+
+        _ => None
+    }
+}
+
+/// The post at `url`, with its media underneath and any quoted post below that, as a blockquote.
+fn patch_text(text: &str, facets: &[Facet], embed: Option<&Value>, url: &str) -> String {
+    let mut text = render_text(text, facets);
+
+    let embed = match embed {
+        Some(embed) => embed,
+        None => return text
+    };
+
+    if let Some(link) = embed_link(embed) {
+        text = format!("{}\n\n{}", text, link);
+    }
+
+    let media: Vec<String> = embed_media(embed, url).iter().map(|media| media.to_markdown()).collect();
+    if !media.is_empty() {
+        text = format!("{}\n\n{}", text, media.join(" "));
+    }
+
+    if let Some(quoted) = embed_quote(embed) {
+        let handle = quoted["author"]["handle"].as_str().unwrap_or_default();
+        let facets: Vec<Facet> = serde_json::from_value(quoted["value"]["facets"].clone()).unwrap_or_default();
+        let quoted_embed = quoted["embeds"].as_array().and_then(|embeds| embeds.first());
+        let quoted_url = permalink(quoted["uri"].as_str().unwrap_or_default(), handle);
+
+        let quote = format!(
+            "[@{}]({} \"View this post on Bluesky\"): {}",
+            escape::markdown_text(handle), quoted_url,
+            patch_text(quoted["value"]["text"].as_str().unwrap_or_default(), &facets, quoted_embed, &quoted_url)
+        );
+
+        let lines: Vec<String> = quote.lines().map(|line| format!("> {}", line)).collect();
+        text = format!("{}\n\n{}", text, lines.join("\n"));
+    }
+
+    text
+}
+
+/// Calls out to Bluesky and retrieves posts (and reposts), then pushes them into a
+/// standard template that'll ultimately be rendered on the HTML side.
+pub fn get_and_transform_posts_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let handle = var("RYMC_BLUESKY_HANDLE")?;
+    let service = var("RYMC_BLUESKY_SERVICE").unwrap_or_else(|_| "https://public.api.bsky.app".into());
+    fetch(&service, &handle)
+}
+
+/// Fetches and renders `handle`'s feed from the AppView at `service`.
+pub fn fetch(service: &str, handle: &str) -> Result<Vec<Activity>, Box<Error>> {
+    let endpoint = format!(
+        "{}/xrpc/app.bsky.feed.getAuthorFeed?actor={}&limit=30",
+        service.trim_end_matches("/"), handle
+    );
+
+    let response: AuthorFeed = reqwest::get(&endpoint)?.json()?;
+
+    let mut activities: Vec<Activity> = vec![];
+    for item in response.feed {
+        let post = &item.post;
+        let url = permalink(&post.uri, &post.author.handle);
+        let text = patch_text(&post.record.text, &post.record.facets, post.embed.as_ref(), &url);
+        let attachments = post.embed.as_ref().map(|embed| embed_media(embed, &url)).unwrap_or_default();
+
+        // Reposts credit whoever wrote the post, like retweets do, and sort by when we reposted it.
+        let (content, ts) = match &item.reason {
+            Some(reason) if reason.reason_type == "app.bsky.feed.defs#reasonRepost" => {
+                (format!("Reposted {} {}", profile_link(&post.author.handle), text), reason.indexed_at)
+            },
+
+            _ => (text, post.record.created_at)
+        };
+
+        activities.push(Activity::new("bluesky", content, DateTime {
+            action: "Posted".into(),
+            url: url,
+            ts: ts
+        }).with_attachments(attachments));
+    }
+
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::serve;
+
+    #[test]
+    fn renders_a_recorded_feed() {
+        let (url, requests) = serve(vec![include_str!("../fixtures/bluesky/author-feed.json").into()]);
+        let activities = fetch(&url, "ryanmcgrath.bsky.social").unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(request.line.starts_with("GET /xrpc/app.bsky.feed.getAuthorFeed?actor=ryanmcgrath.bsky.social&limit=30 "));
+
+        assert_eq!(activities.len(), 6);
+        assert_eq!(
+            activities[0].content,
+            "Hi [@alice.bsky.social](https://bsky.app/profile/did:plc:alice \"View @alice.bsky.social on Bluesky\") see \
+             [example.com](https://example.com/) 🦋 [\\#rust](https://bsky.app/hashtag/rust \"View posts tagged #rust on Bluesky\")\n\n\
+             ![A butterfly](https://cdn.bsky.app/img/feed_fullsize/butterfly.jpg)"
+        );
+        assert_eq!(activities[0].datetime.url, "https://bsky.app/profile/ryanmcgrath.bsky.social/post/3kpost1");
+        assert_eq!(activities[0].attachments.len(), 1);
+
+        assert_eq!(
+            activities[1].content,
+            "Reposted [@alice.bsky.social](https://bsky.app/profile/alice.bsky.social \"View @alice.bsky.social on Bluesky\") Plain text"
+        );
+        assert_eq!(activities[1].datetime.ts, NaiveDateTime::parse_from_str("2019-01-02T12:00:00", "%Y-%m-%dT%H:%M:%S").unwrap());
+    }
+
+    #[test]
+    fn renders_quotes_and_link_cards() {
+        let (url, _) = serve(vec![include_str!("../fixtures/bluesky/author-feed.json").into()]);
+        let activities = fetch(&url, "ryanmcgrath.bsky.social").unwrap();
+
+        // A quote gets every line of the quoted post blockquoted.
+        assert_eq!(
+            activities[3].content,
+            "This, exactly\n\n\
+             > [@alice.bsky.social](https://bsky.app/profile/alice.bsky.social/post/3kquoted1 \"View this post on Bluesky\"): First line\n\
+             > \n\
+             > Second line"
+        );
+        assert!(activities[3].attachments.is_empty());
+
+        // A quote with media has both: the media first, then the quote.
+        assert_eq!(
+            activities[4].content,
+            "Same bird\n\n![](https://cdn.bsky.app/img/feed_fullsize/bird.jpg)\n\n\
+             > [@alice.bsky.social](https://bsky.app/profile/alice.bsky.social/post/3kquoted2 \"View this post on Bluesky\"): A bird"
+        );
+        assert_eq!(activities[4].attachments.len(), 1);
+        assert_eq!(activities[4].attachments[0].url, "https://cdn.bsky.app/img/feed_fullsize/bird.jpg");
+        assert_eq!(activities[4].attachments[0].alt_text, None);
+
+        assert_eq!(activities[5].content, "Worth a read\n\n[Feeds & \\[things\\]](https://example.com/posts/1)");
+    }
+
+    #[test]
+    fn skips_bad_facets() {
+        let (url, _) = serve(vec![include_str!("../fixtures/bluesky/author-feed.json").into()]);
+        let activities = fetch(&url, "ryanmcgrath.bsky.social").unwrap();
+
+        // Backwards, out of range, and overlapping facets all get dropped; the good one stays.
+        assert_eq!(activities[2].content, "[Broken](https://a.example/) facets here");
+    }
+
+    #[test]
+    fn links_video_to_the_post() {
+        let embed = serde_json::json!({
+            "$type": "app.bsky.embed.video#view",
+            "playlist": "https://video.bsky.app/watch/did%3Aplc%3Aryan/bafk/playlist.m3u8",
+            "thumbnail": "https://video.bsky.app/watch/did%3Aplc%3Aryan/bafk/thumbnail.jpg",
+            "alt": "A cat"
+        });
+
+        let url = "https://bsky.app/profile/ryanmcgrath.bsky.social/post/3kvideo";
+        assert_eq!(
+            patch_text("Look", &[], Some(&embed), url),
+            "Look\n\n[![A cat](https://video.bsky.app/watch/did%3Aplc%3Aryan/bafk/thumbnail.jpg)]\
+             (https://bsky.app/profile/ryanmcgrath.bsky.social/post/3kvideo \"Play this video\")"
+        );
+    }
+}
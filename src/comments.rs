@@ -0,0 +1,505 @@
+//! comments.rs
+//!
+//! Comment bodies come in as GFM, full of things our site doesn't render: tables,
+//! task lists, raw HTML, emoji shortcodes, bare references. This walks the markdown,
+//! rewrites those bits in place, and cuts long comments down to an excerpt. What
+//! counts as a reference differs per site, so that part gets passed in.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::{env::var, ops::Range};
+use linkify::{LinkFinder, LinkKind};
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use regex::{Captures, Regex};
+use unicode_segmentation::UnicodeSegmentation;
+
+lazy_static! {
+    static ref QUOTED_REPLY_REGEX: Regex = Regex::new(
+        r"(?m)^(?:> ?)?On [^\n]*(?:\n[^\n]*)?wrote:[ \t]*\n(?:[ \t]*\n|>[^\n]*(?:\n|\z))*\z"
+    ).unwrap();
+
+    static ref EMOJI_SHORTCODE_REGEX: Regex = Regex::new(r":([a-z0-9_+-]+):").unwrap();
+    static ref HTML_COMMENT_REGEX: Regex = Regex::new(r"(?s)<!--.*?-->").unwrap();
+    static ref HTML_BREAK_REGEX: Regex = Regex::new(r"(?i)<br\s*/?>").unwrap();
+    static ref HTML_TAG_REGEX: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref HTML_RAW_TEXT_REGEX: Regex = Regex::new(r"(?i)<(script|style)\b[^>]*>").unwrap();
+
+    static ref EXCERPT_LENGTH: ExcerptLength = ExcerptLength::from_env();
+}
+
+/// Drops a trailing quoted email reply (the "On <date>, <someone> wrote:" line and the
+/// quote block after it), which is what you get when people reply to notifications.
+fn strip_quoted_reply(s: &str) -> &str {
+    match QUOTED_REPLY_REGEX.find(s) {
+        Some(m) => s[..m.start()].trim_end(),
+        None => s
+    }
+}
+
+/// Strips the tags out of a chunk of raw HTML, along with anything inside `<script>` or
+/// `<style>`. Those can span several chunks, so `raw` carries the one we're inside of
+/// (if any) from one call to the next.
+fn strip_html(html: &str, raw: &mut Option<String>) -> String {
+    let html = HTML_COMMENT_REGEX.replace_all(html, "");
+    let mut output = String::with_capacity(html.len());
+    let mut rest: &str = &html;
+
+    loop {
+        if let Some(tag) = raw.take() {
+            // ASCII lowercasing keeps byte offsets where they were.
+            match rest.to_ascii_lowercase().find(&format!("</{}", tag)) {
+                Some(i) => { rest = rest[i..].split_once('>').map(|(_, after)| after).unwrap_or(""); },
+                None => {
+                    *raw = Some(tag);
+                    break;
+                }
+            }
+        }
+
+        match HTML_RAW_TEXT_REGEX.captures(rest) {
+            Some(captures) => {
+                let open = captures.get(0).unwrap();
+                output.push_str(&rest[..open.start()]);
+                *raw = Some(captures[1].to_ascii_lowercase());
+                rest = &rest[open.end()..];
+            },
+
+            None => {
+                output.push_str(rest);
+                break;
+            }
+        }
+    }
+
+    let output = HTML_BREAK_REGEX.replace_all(&output, "  \n");
+    HTML_TAG_REGEX.replace_all(&output, "").into_owned()
+}
+
+/// Walks the markdown in `s` and works out what needs rewriting, as (byte range,
+/// replacement) pairs in source order:
+///
+/// - Plain prose (not inside code spans, fenced/indented code or existing links) gets
+///   linkified, has emoji shortcodes swapped in, and goes through `references`.
+/// - Task list checkboxes become ☐/☑, since not every renderer knows about them.
+/// - Tables get flattened to one list item per row.
+/// - Raw HTML is stripped down to its text, and scripts and styles dropped outright.
+fn rewrites<F>(s: &str, references: &F) -> Vec<(Range<usize>, String)> where F: Fn(&str) -> String {
+    let mut rewrites: Vec<(Range<usize>, String)> = vec![];
+    let mut prose: Option<Range<usize>> = None;
+    let mut depth = 0;
+    let mut table: Option<(Range<usize>, String, Vec<Vec<String>>)> = None;
+    let mut raw: Option<String> = None;
+
+    for (event, range) in Parser::new_ext(s, Options::all()).into_offset_iter() {
+        // Tables get rendered wholesale once we've seen every cell.
+        if let Some((_, _, rows)) = table.as_mut() {
+            match event {
+                Event::Start(Tag::TableHead) | Event::Start(Tag::TableRow) => { rows.push(vec![]); },
+
+                Event::Start(Tag::TableCell) => {
+                    let cell = s[range].trim().trim_matches('|').trim().replace("\\|", "|");
+                    if let Some(row) = rows.last_mut() {
+                        row.push(clean_text(&cell, references));
+                    }
+                },
+
+                // Every line after the first needs whatever the table's container (e.g, a
+                // blockquote) put in front of it, and a blank line after keeps whatever
+                // follows from running on into the last item.
+                Event::End(Tag::Table(_)) => {
+                    let (range, prefix, rows) = table.take().unwrap();
+                    let rows: Vec<String> = rows.iter().map(|row| format!("- {}", row.join(" · "))).collect();
+                    let mut replacement = rows.join(&format!("\n{}", prefix));
+
+                    if range.end < s.len() {
+                        replacement.push_str(&format!("\n{}\n", prefix.trim_end()));
+                    }
+
+                    rewrites.push((range, replacement));
+                },
+
+                _ => {}
+            }
+
+            continue;
+        }
+
+        // Text that doesn't match the source verbatim came from an escape or an entity;
+        // leave those exactly as the author wrote them.
+        let is_prose = match &event {
+            Event::Text(text) => depth == 0 && raw.is_none() && &s[range.clone()] == text.as_ref(),
+            _ => false
+        };
+
+        if !is_prose {
+            if let Some(prose) = prose.take() {
+                rewrites.push((prose.clone(), linkify_prose(&s[prose], references)));
+            }
+        }
+
+        match event {
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) | Event::Start(Tag::CodeBlock(_)) => { depth += 1; },
+            Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) | Event::End(Tag::CodeBlock(_)) => { depth -= 1; },
+            Event::Start(Tag::Table(_)) => {
+                // Blockquote markers carry over as they are; list markers become indentation.
+                let line = s[..range.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+                let prefix = s[line..range.start].chars().map(|c| match c == '>' || c.is_whitespace() {
+                    true => c,
+                    false => ' '
+                }).collect();
+
+                table = Some((range, prefix, vec![]));
+            },
+
+            Event::TaskListMarker(checked) => {
+                rewrites.push((range, match checked {
+                    true => "☑".into(),
+                    false => "☐".into()
+                }));
+            },
+
+            Event::Html(html) if depth == 0 => { rewrites.push((range, strip_html(&html, &mut raw))); },
+            Event::Text(_) | Event::Code(_) if raw.is_some() => { rewrites.push((range, String::new())); },
+
+            Event::Text(_) if is_prose => {
+                let mut range = range;
+                if range.start > 0 && s.as_bytes()[range.start - 1] == b'\\' {
+                    let escaped = s[range.start..].chars().next().map(|c| c.len_utf8()).unwrap_or(0);
+                    range.start = std::cmp::min(range.end, range.start + escaped);
+                }
+
+                prose = match prose.take() {
+                    Some(prose) if prose.end == range.start => Some(prose.start..range.end),
+                    Some(prose) => {
+                        rewrites.push((prose.clone(), linkify_prose(&s[prose], references)));
+                        Some(range)
+                    },
+                    None => Some(range)
+                };
+            },
+
+            _ => {}
+        }
+    }
+
+    if let Some(prose) = prose {
+        rewrites.push((prose.clone(), linkify_prose(&s[prose], references)));
+    }
+
+    rewrites
+}
+
+/// Cleans up comment text: markdown-ifies links, autolinks whatever `references` picks
+/// out of the prose (e.g, @mentions, issues and commits, each site in its own syntax),
+/// and normalizes the GFM bits our site doesn't render.
+pub fn clean_text<F>(s: &str, references: &F) -> String where F: Fn(&str) -> String {
+    let text = strip_quoted_reply(s);
+    let mut output = String::with_capacity(text.len());
+    let mut cursor = 0;
+
+    for (range, replacement) in rewrites(text, references) {
+        output.push_str(&text[cursor..range.start]);
+        output.push_str(&replacement);
+        cursor = range.end;
+    }
+
+    output.push_str(&text[cursor..]);
+    output
+}
+
+/// Labels an issue-style reference (`#12`, `!3`) the way the sites do: bare when it's in
+/// the repo we're already in, prefixed with `target` when it's somewhere else.
+pub fn issue_label(reference: &str, target: &str, current: &str) -> String {
+    match target == current {
+        true => reference.to_string(),
+        false => format!("{}{}", target, reference)
+    }
+}
+
+/// Same idea as `issue_label`, for commits: the short SHA, qualified as `target@sha` when
+/// it's another repo's. `None` if `sha` probably isn't one: plenty of numbers and words
+/// (`1234567`, `deadbeef`, `facade1`) are valid hex too, so it takes at least one letter
+/// and two digits to count.
+pub fn commit_label(sha: &str, target: &str, current: &str) -> Option<String> {
+    if !looks_like_sha(sha) {
+        return None;
+    }
+
+    Some(match target == current {
+        true => sha[..7].to_string(),
+        false => format!("{}@{}", target, &sha[..7])
+    })
+}
+
+fn looks_like_sha(s: &str) -> bool {
+    s.chars().filter(|c| c.is_ascii_digit()).count() >= 2 && s.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+/// Swaps GitHub-style `:shortcode:` emoji for the real thing, leaving unknown ones alone.
+fn replace_shortcodes(s: &str) -> String {
+    EMOJI_SHORTCODE_REGEX.replace_all(s, |captures: &Captures| {
+        match emojis::get_by_shortcode(&captures[1]) {
+            Some(emoji) => emoji.as_str().to_string(),
+            None => captures[0].to_string()
+        }
+    }).into_owned()
+}
+
+/// Linkifies a run of plain prose. Each link is wrapped exactly once, in place, and
+/// everything in between gets the reference and emoji treatment.
+fn linkify_prose<F>(s: &str, references: &F) -> String where F: Fn(&str) -> String {
+    let mut link_finder = LinkFinder::new();
+    link_finder.kinds(&[LinkKind::Url]);
+
+    let mut output = String::with_capacity(s.len());
+    let mut cursor = 0;
+
+    for link in link_finder.links(s) {
+        output.push_str(&references(&replace_shortcodes(&s[cursor..link.start()])));
+        output.push_str(&format!("[{}]({})", link.as_str(), link.as_str()));
+        cursor = link.end();
+    }
+
+    output.push_str(&references(&replace_shortcodes(&s[cursor..])));
+    output
+}
+
+/// How much of a long comment makes it into the feed, via `RYMC_COMMENT_EXCERPT`: either
+/// `paragraph` (the first block), a number of graphemes, or `off` for the whole thing.
+#[derive(Debug)]
+pub enum ExcerptLength {
+    Off,
+    Paragraph,
+    Graphemes(usize)
+}
+
+impl ExcerptLength {
+    pub fn from_env() -> Self {
+        // This was `RYMC_GITHUB_EXCERPT` back when GitHub was the only source with comments.
+        let (key, config) = ["RYMC_COMMENT_EXCERPT", "RYMC_GITHUB_EXCERPT"].iter()
+            .find_map(|key| var(key).ok().map(|config| (*key, config)))
+            .unwrap_or(("RYMC_COMMENT_EXCERPT", "280".into()));
+
+        ExcerptLength::parse(&config).unwrap_or_else(|| {
+            eprintln!("Invalid {} \"{}\" (expected off, paragraph or a number); using 280.", key, config);
+            ExcerptLength::Graphemes(280)
+        })
+    }
+
+    fn parse(config: &str) -> Option<Self> {
+        match config.trim() {
+            "off" => Some(ExcerptLength::Off),
+            "paragraph" => Some(ExcerptLength::Paragraph),
+            n => n.parse().map(ExcerptLength::Graphemes).ok()
+        }
+    }
+}
+
+/// How many graphemes come before each of `offsets` (sorted), plus the total, from a
+/// single walk over `s`.
+fn grapheme_counts(s: &str, offsets: &[usize]) -> (Vec<usize>, usize) {
+    let mut graphemes = s.grapheme_indices(true).peekable();
+    let mut count = 0;
+
+    let counts = offsets.iter().map(|&offset| {
+        while graphemes.next_if(|&(i, _)| i < offset).is_some() {
+            count += 1;
+        }
+
+        count
+    }).collect();
+
+    (counts, count + graphemes.count())
+}
+
+/// Trims `s` down according to `length`, returning `None` if it's short enough as-is.
+/// Cuts only ever land between top-level blocks or on whitespace in plain paragraph
+/// text, so we never end up inside a link, a code span or a fence. If there's nowhere
+/// to cut that fits, the excerpt is empty, and all that's left is the link to the rest.
+fn excerpt(s: &str, length: &ExcerptLength) -> Option<String> {
+    let s = s.trim_end();
+    let limit = match length {
+        ExcerptLength::Off => { return None; },
+        ExcerptLength::Paragraph => None,
+        ExcerptLength::Graphemes(n) => Some(*n)
+    };
+
+    let mut blocks: Vec<usize> = vec![];
+    let mut words: Vec<usize> = vec![];
+    let mut stack: Vec<bool> = vec![];
+
+    for (event, range) in Parser::new_ext(s, Options::all()).into_offset_iter() {
+        match event {
+            Event::Start(tag) => { stack.push(matches!(tag, Tag::Paragraph)); },
+
+            Event::End(_) => {
+                stack.pop();
+                if stack.is_empty() { blocks.push(range.end); }
+            },
+
+            Event::Text(text) if stack == [true] && &s[range.clone()] == text.as_ref() => {
+                words.extend(text.char_indices().filter(|(_, c)| c.is_whitespace()).map(|(i, _)| range.start + i));
+            },
+
+            _ => {}
+        }
+    }
+
+    let first_block = blocks.first().cloned().unwrap_or(s.len());
+
+    let n = match limit {
+        None if first_block < s.len() => { return Some(s[..first_block].trim_end().to_string()); },
+        None => { return None; },
+        Some(n) => n
+    };
+
+    // Every place we could cut, in order, with how many graphemes come before it.
+    let mut cuts: Vec<usize> = blocks.iter().chain(words.iter().filter(|&&end| end <= first_block)).cloned().collect();
+    cuts.sort();
+    cuts.dedup();
+
+    let (counts, total) = grapheme_counts(s, &cuts);
+    if total <= n {
+        return None;
+    }
+
+    let fits = |end: &&usize| counts[cuts.binary_search(*end).unwrap()] <= n;
+
+    if let Some(end) = blocks.iter().rev().find(fits) {
+        return Some(s[..*end].trim_end().to_string());
+    }
+
+    match words.iter().rev().filter(|&&end| end <= first_block).find(fits) {
+        Some(end) if !s[..*end].trim().is_empty() => Some(format!("{}…", s[..*end].trim_end())),
+        _ => Some(String::new())
+    }
+}
+
+/// Whether `s` ends in paragraph text - that is, whether something tacked onto the end
+/// stays in that paragraph, rather than landing inside a fence, list item or quote.
+fn ends_in_paragraph(s: &str) -> bool {
+    let mut depth = 0;
+    let mut last = false;
+
+    for event in Parser::new_ext(s, Options::all()) {
+        match event {
+            Event::Start(tag) => {
+                if depth == 0 { last = matches!(tag, Tag::Paragraph); }
+                depth += 1;
+            },
+
+            Event::End(_) => { depth -= 1; },
+            _ if depth == 0 => { last = false; },
+            _ => {}
+        }
+    }
+
+    last
+}
+
+/// Renders a comment body, cutting it down to an excerpt (with a "read more" link to
+/// `html_url`, on `site`) if it's too long. Callers tack more on after (e.g, " on
+/// [repo]"), so if the comment doesn't end in a paragraph, it ends in a blank line.
+pub fn render_comment<F>(body: &str, html_url: &str, site: &str, references: &F) -> String where F: Fn(&str) -> String {
+    render_excerpt(body, &EXCERPT_LENGTH, html_url, site, references)
+}
+
+fn render_excerpt<F>(body: &str, length: &ExcerptLength, html_url: &str, site: &str, references: &F) -> String where F: Fn(&str) -> String {
+    let body = strip_quoted_reply(body);
+
+    let (text, link) = match excerpt(body, length) {
+        Some(ref excerpt) if excerpt.is_empty() => {
+            return format!("[Read the full comment]({} \"Read the full comment on {}\")", html_url, site);
+        },
+
+        Some(excerpt) => (
+            clean_text(&excerpt, references),
+            format!("[read more]({} \"Read the full comment on {}\")", html_url, site)
+        ),
+
+        None => (clean_text(body, references), String::new())
+    };
+
+    match ends_in_paragraph(&text) {
+        true if link.is_empty() => text,
+        true => format!("{} {}", text, link),
+        false => format!("{}\n\n{}", text, link)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qualifies_references_to_other_repos() {
+        assert_eq!(issue_label("#12", "ryanmcgrath/social", "ryanmcgrath/social"), "#12");
+        assert_eq!(issue_label("!3", "group/sub/project", "ryanmcgrath/social"), "group/sub/project!3");
+
+        assert_eq!(commit_label("a1b2c3d4e5", "ryanmcgrath/social", "ryanmcgrath/social"), Some("a1b2c3d".into()));
+        assert_eq!(commit_label("a1b2c3d4e5", "rust-lang/rust", "ryanmcgrath/social"), Some("rust-lang/rust@a1b2c3d".into()));
+        assert_eq!(commit_label("1234567", "ryanmcgrath/social", "ryanmcgrath/social"), None);
+        assert_eq!(commit_label("deadbeef", "ryanmcgrath/social", "ryanmcgrath/social"), None);
+    }
+
+    #[test]
+    fn excerpts_at_block_boundaries() {
+        let body = "First paragraph.\n\nSecond paragraph, which goes on a bit.";
+        assert_eq!(excerpt(body, &ExcerptLength::Graphemes(20)), Some("First paragraph.".into()));
+        assert_eq!(excerpt(body, &ExcerptLength::Paragraph), Some("First paragraph.".into()));
+        assert_eq!(excerpt(body, &ExcerptLength::Graphemes(100)), None);
+        assert_eq!(excerpt(body, &ExcerptLength::Off), None);
+    }
+
+    #[test]
+    fn excerpts_on_whitespace_counting_graphemes() {
+        // Each family is one grapheme, however many bytes and chars it takes.
+        let body = "👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧";
+        assert_eq!(excerpt(body, &ExcerptLength::Graphemes(9)), None);
+        assert_eq!(excerpt(body, &ExcerptLength::Graphemes(8)), Some("👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧…".into()));
+    }
+
+    #[test]
+    fn leaves_only_the_link_when_nothing_fits() {
+        let body = "```\nlet x = 1;\nlet y = 2;\n```\n\nMore.";
+        assert_eq!(excerpt(body, &ExcerptLength::Graphemes(10)), Some("".into()));
+        assert_eq!(excerpt("Supercalifragilistic expialidocious", &ExcerptLength::Graphemes(5)), Some("".into()));
+    }
+
+    #[test]
+    fn rejects_unparsable_excerpt_lengths() {
+        assert!(ExcerptLength::parse("lots").is_none());
+        assert!(ExcerptLength::parse("-1").is_none());
+        assert!(matches!(ExcerptLength::parse(" 140 "), Some(ExcerptLength::Graphemes(140))));
+        assert!(matches!(ExcerptLength::parse("paragraph"), Some(ExcerptLength::Paragraph)));
+    }
+
+    fn render(body: &str, length: usize) -> String {
+        render_excerpt(body, &ExcerptLength::Graphemes(length), "https://github.com/o/r#1", "GitHub", &|s: &str| s.to_string())
+    }
+
+    #[test]
+    fn puts_read_more_inline_after_a_paragraph() {
+        assert_eq!(
+            render("First paragraph.\n\nSecond paragraph, which goes on a bit.", 20),
+            "First paragraph. [read more](https://github.com/o/r#1 \"Read the full comment on GitHub\")"
+        );
+    }
+
+    #[test]
+    fn puts_read_more_after_a_fence_list_or_quote() {
+        let link = "[read more](https://github.com/o/r#1 \"Read the full comment on GitHub\")";
+        let more = "\n\nAnd then a whole lot more, which doesn't fit.";
+
+        assert_eq!(render(&format!("```\nlet x = 1;\n```{}", more), 30), format!("```\nlet x = 1;\n```\n\n{}", link));
+        assert_eq!(render(&format!("- one\n- two{}", more), 30), format!("- one\n- two\n\n{}", link));
+        assert_eq!(render(&format!("> quoted text{}", more), 30), format!("> quoted text\n\n{}", link));
+    }
+
+    #[test]
+    fn leaves_room_after_a_comment_ending_in_a_fence() {
+        assert_eq!(render("Look:\n\n```\nlet x = 1;\n```", 280), "Look:\n\n```\nlet x = 1;\n```\n\n");
+        assert_eq!(render("Short and sweet.", 280), "Short and sweet.");
+    }
+}
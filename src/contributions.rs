@@ -0,0 +1,253 @@
+//! contributions.rs
+//!
+//! Fetches the GitHub contribution calendar (the green squares) via the GraphQL
+//! API. The REST events endpoint only goes back 90 days and only covers public
+//! events, so it's no good for a heatmap.
+//!
+//! This is optional, and only runs if `RYMC_GITHUB_CONTRIBUTIONS` is `true`.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::error::Error;
+use std::fmt;
+
+use crate::env::flag_from_env;
+
+const QUERY: &'static str = "query($login: String!) {
+    user(login: $login) {
+        contributionsCollection {
+            totalCommitContributions
+            totalIssueContributions
+            totalPullRequestContributions
+            totalPullRequestReviewContributions
+            totalRepositoryContributions
+            restrictedContributionsCount
+            contributionCalendar {
+                totalContributions
+                weeks {
+                    contributionDays {
+                        date
+                        contributionCount
+                        color
+                    }
+                }
+            }
+        }
+    }
+}";
+
+#[derive(Debug)]
+pub struct GraphQLError {
+    message: String
+}
+
+impl Error for GraphQLError {}
+
+impl fmt::Display for GraphQLError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GitHub GraphQL request failed: {}", self.message)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Response {
+    data: Option<Data>,
+
+    #[serde(default)]
+    errors: Vec<ResponseError>
+}
+
+#[derive(Deserialize, Debug)]
+struct ResponseError {
+    message: String
+}
+
+#[derive(Deserialize, Debug)]
+struct Data {
+    user: Option<User>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct User {
+    contributions_collection: ContributionsCollection
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ContributionsCollection {
+    total_commit_contributions: i64,
+    total_issue_contributions: i64,
+    total_pull_request_contributions: i64,
+    total_pull_request_review_contributions: i64,
+    total_repository_contributions: i64,
+    restricted_contributions_count: i64,
+    contribution_calendar: ContributionCalendar
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ContributionCalendar {
+    total_contributions: i64,
+    weeks: Vec<Week>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Week {
+    contribution_days: Vec<ContributionDay>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ContributionDay {
+    date: String,
+    contribution_count: i64,
+    color: String
+}
+
+/// Totals per contribution type, over the same window as the calendar.
+#[derive(Serialize, Debug)]
+pub struct ContributionTotals {
+    pub commits: i64,
+    pub issues: i64,
+    pub pull_requests: i64,
+    pub reviews: i64,
+    pub repositories: i64,
+    pub restricted: i64
+}
+
+/// A single square on the calendar. `date` is `YYYY-MM-DD`.
+#[derive(Serialize, Debug)]
+pub struct Day {
+    pub date: String,
+    pub count: i64,
+    pub color: String
+}
+
+/// What gets written to `github-contributions.json`: one entry per day, oldest first.
+#[derive(Serialize, Debug)]
+pub struct Contributions {
+    pub total: i64,
+    pub totals: ContributionTotals,
+    pub days: Vec<Day>
+}
+
+pub fn enabled() -> bool {
+    flag_from_env("RYMC_GITHUB_CONTRIBUTIONS")
+}
+
+/// Asks `endpoint` (normally `https://api.github.com/graphql`, or `RYMC_GITHUB_GRAPHQL_URL`
+/// if set) for `login`'s contribution calendar over the last year.
+pub fn fetch(endpoint: &str, login: &str, access_token: &str) -> Result<Contributions, Box<Error>> {
+    let body = serde_json::json!({
+        "query": QUERY,
+        "variables": { "login": login }
+    });
+
+    let response: Response = reqwest::Client::new().post(endpoint)
+        .header("Authorization", format!("bearer {}", access_token))
+        .header("User-Agent", "activity-scraper")
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&body)?)
+        .send()?
+        .json()?;
+
+    if let Some(error) = response.errors.first() {
+        return Err(Box::new(GraphQLError { message: error.message.clone() }));
+    }
+
+    let collection = response.data.and_then(|data| data.user).ok_or_else(|| GraphQLError {
+        message: format!("no such user ({})", login)
+    })?.contributions_collection;
+
+    let days = collection.contribution_calendar.weeks.into_iter().flat_map(|week| {
+        week.contribution_days.into_iter()
+    }).map(|day| Day {
+        date: day.date,
+        count: day.contribution_count,
+        color: day.color
+    }).collect();
+
+    Ok(Contributions {
+        total: collection.contribution_calendar.total_contributions,
+        totals: ContributionTotals {
+            commits: collection.total_commit_contributions,
+            issues: collection.total_issue_contributions,
+            pull_requests: collection.total_pull_request_contributions,
+            reviews: collection.total_pull_request_review_contributions,
+            repositories: collection.total_repository_contributions,
+            restricted: collection.restricted_contributions_count
+        },
+        days: days
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::serve;
+
+    const CALENDAR: &'static str = r##"{
+        "data": {
+            "user": {
+                "contributionsCollection": {
+                    "totalCommitContributions": 40,
+                    "totalIssueContributions": 3,
+                    "totalPullRequestContributions": 5,
+                    "totalPullRequestReviewContributions": 2,
+                    "totalRepositoryContributions": 1,
+                    "restrictedContributionsCount": 7,
+                    "contributionCalendar": {
+                        "totalContributions": 58,
+                        "weeks": [
+                            { "contributionDays": [
+                                { "date": "2019-01-06", "contributionCount": 0, "color": "#ebedf0" },
+                                { "date": "2019-01-07", "contributionCount": 4, "color": "#c6e48b" }
+                            ] },
+                            { "contributionDays": [
+                                { "date": "2019-01-13", "contributionCount": 9, "color": "#239a3b" }
+                            ] }
+                        ]
+                    }
+                }
+            }
+        }
+    }"##;
+
+    #[test]
+    fn fetches_the_calendar() {
+        let (url, requests) = serve(vec![CALENDAR.into()]);
+        let contributions = fetch(&format!("{}/graphql", url), "ryanmcgrath", "token").unwrap();
+
+        assert_eq!(contributions.total, 58);
+        assert_eq!(contributions.totals.commits, 40);
+        assert_eq!(contributions.totals.restricted, 7);
+
+        let days: Vec<(&str, i64)> = contributions.days.iter().map(|day| (day.date.as_str(), day.count)).collect();
+        assert_eq!(days, vec![("2019-01-06", 0), ("2019-01-07", 4), ("2019-01-13", 9)]);
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.line, "POST /graphql HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("bearer token"));
+
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["variables"]["login"], "ryanmcgrath");
+        assert!(body["query"].as_str().unwrap().contains("contributionCalendar"));
+    }
+
+    #[test]
+    fn surfaces_graphql_errors() {
+        let (url, _) = serve(vec![r#"{ "data": null, "errors": [{ "message": "Bad credentials" }] }"#.into()]);
+        let error = fetch(&url, "ryanmcgrath", "token").unwrap_err();
+        assert!(error.to_string().contains("Bad credentials"));
+    }
+
+    #[test]
+    fn rejects_unknown_users() {
+        let (url, _) = serve(vec![r#"{ "data": { "user": null } }"#.into()]);
+        let error = fetch(&url, "nobody", "token").unwrap_err();
+        assert!(error.to_string().contains("no such user (nobody)"));
+    }
+}
@@ -12,7 +12,7 @@ use std::error::Error;
 use serde::{Deserializer, Deserialize};
 use chrono::NaiveDateTime;
 
-use crate::{Activity, DateTime, markdown_link_title_escape};
+use crate::{Activity, DateTime, escape};
 
 #[derive(Deserialize, Debug)]
 pub struct ImageSet {
@@ -53,19 +53,19 @@ pub fn get_and_transform_activity_to_html() -> Result<Vec<Activity>, Box<Error>>
 
     // Write it ahead of time, as the Designs tab also uses this data
     let path = std::env::var("RYMC_ACTIVITY_PATH")?;
-    std::fs::write(&format!("{}/dribbble.json", path), response)?;
+    std::fs::write(format!("{}/dribbble.json", path), response)?;
 
     let mut activities: Vec<Activity> = vec![];
     for shot in shots {
         let tags: Vec<String> = shot.tags.iter().map(|tag| format!(
             "[#{}](https://dribbble.com/ryanmcgrath/tags/{} \"View shots tagged {} on Dribbble\")",
-            tag, tag, markdown_link_title_escape(&tag)
+            escape::markdown_text(tag), escape::markdown_url(tag), escape::markdown_link_title(tag)
         )).collect();
         
         let content = format!(
             "Unveiled a new Shot: [{}]({} \"View {} on Dribbble\") [![{}]({})]({} \"View {} on Dribbble\")\n\n{}",
-            shot.title, shot.html_url, markdown_link_title_escape(&shot.title),
-            shot.title, shot.images.teaser, shot.html_url, markdown_link_title_escape(&shot.title), tags.join(" ")
+            escape::markdown_text(&shot.title), shot.html_url, escape::markdown_link_title(&shot.title),
+            escape::markdown_text(&shot.title), shot.images.teaser, shot.html_url, escape::markdown_link_title(&shot.title), tags.join(" ")
         );
         
         activities.push(Activity::new("dribbble", content, DateTime {
@@ -0,0 +1,127 @@
+//! entities.rs
+//!
+//! Rewrites entities (mentions, hashtags, links, etc) in post text by their
+//! offsets, rather than by searching for them. Searching goes wrong in all sorts
+//! of ways - `@foo` matching inside `@foobar`, hashtags matching inside URLs, a
+//! link getting rewritten twice - and the APIs tell us exactly where everything
+//! is anyway.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+/// A chunk of text to swap out. `start` and `end` are character (code point) offsets,
+/// `end` exclusive.
+#[derive(Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub replacement: String
+}
+
+impl Span {
+    pub fn new(indices: [u32; 2], replacement: String) -> Self {
+        Span {
+            start: indices[0] as usize,
+            end: indices[1] as usize,
+            replacement: replacement
+        }
+    }
+}
+
+/// Replaces every span in `text` in a single pass, so nothing ever gets rewritten twice.
+/// Spans that overlap an earlier one (or fall outside the text) are dropped, and the text
+/// in between gets run through `escape`. If `range` is given, only the characters inside
+/// it make it into the output - e.g, Twitter's `display_text_range`, which leaves out
+/// leading reply mentions and trailing media links.
+///
+/// `text` has to be the text the offsets were counted against - for Twitter, that's with
+/// `&amp;`, `&lt;` and `&gt;` already decoded, as each counts as a single character.
+pub fn splice(text: &str, mut spans: Vec<Span>, range: Option<[u32; 2]>, escape: fn(&str) -> String) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let (from, to) = match range {
+        Some([start, end]) => (start as usize, std::cmp::min(end as usize, chars.len())),
+        None => (0, chars.len())
+    };
+
+    spans.sort_by_key(|span| span.start);
+
+    let mut output = String::with_capacity(text.len());
+    let mut cursor = from;
+
+    for span in spans {
+        if span.start < cursor || span.end > to || span.start > span.end {
+            continue;
+        }
+
+        output.push_str(&escape(&chars[cursor..span.start].iter().collect::<String>()));
+        output.push_str(&span.replacement);
+        cursor = span.end;
+    }
+
+    if cursor < to {
+        output.push_str(&escape(&chars[cursor..to].iter().collect::<String>()));
+    }
+
+    output.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(start: u32, end: u32) -> Span {
+        Span::new([start, end], "[link](https://rymc.io/)".into())
+    }
+
+    fn keep(s: &str) -> String {
+        s.to_string()
+    }
+
+    /// Where `needle` starts in `text`, counted the way the APIs count it: in characters.
+    fn at(text: &str, needle: &str) -> u32 {
+        text[..text.find(needle).unwrap()].chars().count() as u32
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        for prefix in &["é", "😀", "𝒜𝒷𝒸", "👩‍💻", "🇯🇵", "👨‍👩‍👧 🇯🇵 😀"] {
+            let text = format!("{} see https://t.co/abc ok", prefix);
+            let start = at(&text, "https://");
+            let end = start + "https://t.co/abc".len() as u32;
+
+            assert_eq!(splice(&text, vec![link(start, end)], None, keep), format!("{} see [link](https://rymc.io/) ok", prefix));
+        }
+    }
+
+    #[test]
+    fn keeps_to_the_display_range() {
+        let text = "@someone 🇯🇵 hi https://t.co/media";
+        let start = at(text, "🇯🇵");
+        let end = at(text, " https://");
+
+        assert_eq!(splice(text, vec![], Some([start, end]), keep), "🇯🇵 hi");
+    }
+
+    #[test]
+    fn drops_overlapping_and_out_of_range_spans() {
+        let text = "👩‍💻 one two";
+        let start = at(text, "one");
+        let spans = vec![
+            Span::new([start, start + 3], "1".into()),
+            Span::new([start + 1, start + 7], "nope".into()),
+            Span::new([start + 4, start + 40], "nope".into()),
+            Span::new([start + 7, start + 4], "nope".into())
+        ];
+
+        assert_eq!(splice(text, spans, None, keep), "👩‍💻 1 two");
+    }
+
+    #[test]
+    fn escapes_only_the_text_in_between() {
+        let text = "*wow* https://t.co/abc";
+        let start = at(text, "https://");
+        let escape = |s: &str| s.replace("*", "\\*");
+
+        assert_eq!(splice(text, vec![link(start, start + 16)], None, escape), "\\*wow\\* [link](https://rymc.io/)");
+    }
+}
@@ -0,0 +1,19 @@
+//! env.rs
+//!
+//! Small helpers for reading settings out of the environment, shared by the sources
+//! that take lists or on/off switches.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+
+/// A comma separated list, trimmed, with empty entries dropped. Unset is an empty list.
+pub fn list_from_env(key: &str) -> Vec<String> {
+    var(key).unwrap_or_default().split(",").map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect()
+}
+
+/// An on/off switch: `true` or `1` is on, anything else (or unset) is off.
+pub fn flag_from_env(key: &str) -> bool {
+    var(key).map(|v| v == "true" || v == "1").unwrap_or(false)
+}
@@ -0,0 +1,141 @@
+//! escape.rs
+//!
+//! Escaping (and un-escaping) for text that ends up in the feed's markdown. Every
+//! source funnels user-supplied text through here before it gets anywhere near a
+//! format string, so a stray `*` or `[` in a title can't wreck the page.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use regex::{Captures, Regex};
+
+lazy_static! {
+    static ref HTML_ENTITY_REGEX: Regex = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+    static ref LINE_START_REGEX: Regex = Regex::new(r"(?m)^(\s*)([-+]|\d+[.)])(\s|$)").unwrap();
+    static ref BREAK_REGEX: Regex = Regex::new(r"(?m)^([ \t]*)([-=][-= \t]*)$").unwrap();
+    static ref NAMED_ENTITY_REGEX: Regex = Regex::new(r"&([a-zA-Z][a-zA-Z0-9]*;)").unwrap();
+}
+
+/// Escapes text for use inside a link title, e.g, `[text](url "title")`.
+pub fn markdown_link_title(s: &str) -> String {
+    s.replace("\"", "&#34;").replace("(", "&#40;").replace(")", "&#41;")
+}
+
+/// Escapes plain text so it renders as-is: inline markup (emphasis, code, links,
+/// HTML, entities) anywhere, and block markup (lists, quotes, headings, rules) at the
+/// start of a line. Safe to use for link text too.
+pub fn markdown_text(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '~' | '|' => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+
+            _ => escaped.push(c)
+        }
+    }
+
+    // Numeric entities are already taken care of, as `#` gets escaped above.
+    let escaped = NAMED_ENTITY_REGEX.replace_all(&escaped, "\\&$1");
+
+    // List markers: `-`, `+`, `1.` and `1)`.
+    let escaped = LINE_START_REGEX.replace_all(&escaped, |captures: &Captures| {
+        let marker = &captures[2];
+        match marker.ends_with('.') || marker.ends_with(')') {
+            true => format!("{}{}\\{}{}", &captures[1], &marker[..marker.len() - 1], &marker[marker.len() - 1..], &captures[3]),
+            false => format!("{}\\{}{}", &captures[1], marker, &captures[3])
+        }
+    });
+
+    // A line of `-`s or `=`s is a rule, or underlines the line above into a heading.
+    BREAK_REGEX.replace_all(&escaped, "$1\\$2").into_owned()
+}
+
+/// Makes a URL safe to drop into `[text](url)` - spaces and parentheses would otherwise
+/// end the destination early.
+pub fn markdown_url(s: &str) -> String {
+    s.replace(" ", "%20").replace("(", "%28").replace(")", "%29")
+}
+
+/// Decodes HTML entities (`&amp;`, `&#39;`, `&#x1F600;`, etc). Twitter and Mastodon both
+/// hand back text with these in it. Anything we don't recognize is left alone.
+pub fn decode_html_entities(s: &str) -> String {
+    HTML_ENTITY_REGEX.replace_all(s, |captures: &Captures| {
+        let entity = &captures[1];
+
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            "hellip" => Some('…'),
+            "mdash" => Some('—'),
+            "ndash" => Some('–'),
+            "lsquo" => Some('‘'),
+            "rsquo" => Some('’'),
+            "ldquo" => Some('“'),
+            "rdquo" => Some('”'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16).ok().and_then(std::char::from_u32)
+            },
+            _ if entity.starts_with("#") => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None
+        };
+
+        match decoded {
+            Some(c) => c.to_string(),
+            None => captures[0].to_string()
+        }
+    }).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_inline_markup() {
+        assert_eq!(markdown_text("*hi* _there_ `code` [a](b) <b> #1 ~x~ a|b \\"), "\\*hi\\* \\_there\\_ \\`code\\` \\[a\\](b) \\<b\\> \\#1 \\~x\\~ a\\|b \\\\");
+        assert_eq!(markdown_text("Salt & pepper, 1. 2) - +"), "Salt & pepper, 1. 2) - +");
+    }
+
+    #[test]
+    fn escapes_list_markers_at_the_start_of_a_line() {
+        assert_eq!(markdown_text("- one\n+ two\n  3. three\n4) four"), "\\- one\n\\+ two\n  3\\. three\n4\\) four");
+        assert_eq!(markdown_text("-\n10)"), "\\-\n10\\)");
+        assert_eq!(markdown_text("-1 and 2.5"), "-1 and 2.5");
+    }
+
+    #[test]
+    fn escapes_rules_and_heading_underlines() {
+        assert_eq!(markdown_text("Title\n---"), "Title\n\\---");
+        assert_eq!(markdown_text("Title\n==="), "Title\n\\===");
+        assert_eq!(markdown_text("***\n- - -\n-- -\n  ___"), "\\*\\*\\*\n\\- - -\n\\-- -\n  \\_\\_\\_");
+        assert_eq!(markdown_text("a -- b\n=> c"), "a -- b\n=\\> c");
+    }
+
+    #[test]
+    fn escapes_entities() {
+        assert_eq!(markdown_text("&amp; &copy; &#39; &#x27; & ;"), "\\&amp; \\&copy; &\\#39; &\\#x27; & ;");
+    }
+
+    #[test]
+    fn escapes_urls_and_link_titles() {
+        assert_eq!(markdown_url("https://en.wikipedia.org/wiki/Rust (language)"), "https://en.wikipedia.org/wiki/Rust%20%28language%29");
+        assert_eq!(markdown_link_title("Say \"hi\" (twice)"), "Say &#34;hi&#34; &#40;twice&#41;");
+    }
+
+    #[test]
+    fn decodes_html_entities() {
+        assert_eq!(decode_html_entities("&amp;&lt;&gt;&quot;&apos;&hellip;"), "&<>\"'…");
+        assert_eq!(decode_html_entities("&#39; &#128512;"), "' 😀");
+        assert_eq!(decode_html_entities("&#x27; &#X1F600;"), "' 😀");
+        assert_eq!(decode_html_entities("&bogus; &amp &;"), "&bogus; &amp &;");
+        assert_eq!(decode_html_entities("&#xD800; &#x110000; &#99999999999;"), "&#xD800; &#x110000; &#99999999999;");
+    }
+}
@@ -0,0 +1,216 @@
+//! feeds.rs
+//!
+//! A catch-all source for anything that publishes a feed - RSS 2.0, Atom or JSON
+//! Feed - e.g, a blog, a podcast, Letterboxd or Goodreads. Feeds are listed in a
+//! JSON file (pointed to by `RYMC_FEEDS`), each with its own type, verb,
+//! template and limit:
+//!
+//! ``` json
+//! [
+//!     {
+//!         "url": "https://rymc.io/feed.xml",
+//!         "type": "blog",
+//!         "action": "Wrote",
+//!         "template": "Wrote a new post: [{title}]({url})\n\n{summary}",
+//!         "limit": 5
+//!     }
+//! ]
+//! ```
+//!
+//! Templates can use `{title}`, `{url}`, `{summary}` and `{feed}` (the feed's own
+//! title), all of which get escaped before they go in.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use std::io::Read;
+use regex::{Captures, Regex};
+
+use crate::{Activity, DateTime, escape};
+
+const SUMMARY_LENGTH: usize = 280;
+
+lazy_static! {
+    static ref HTML_TAG_REGEX: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref WHITESPACE_REGEX: Regex = Regex::new(r"\s+").unwrap();
+    static ref PLACEHOLDER_REGEX: Regex = Regex::new(r"\{(title|url|summary|feed)\}").unwrap();
+}
+
+fn default_action() -> String {
+    "Posted".into()
+}
+
+fn default_template() -> String {
+    "[{title}]({url})".into()
+}
+
+fn default_limit() -> usize {
+    5
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FeedConfig {
+    pub url: String,
+
+    #[serde(rename = "type")]
+    pub activity_type: String,
+
+    #[serde(default = "default_action")]
+    pub action: String,
+
+    #[serde(default = "default_template")]
+    pub template: String,
+
+    #[serde(default = "default_limit")]
+    pub limit: usize
+}
+
+/// Only runs if there's a feed list configured.
+pub fn enabled() -> bool {
+    var("RYMC_FEEDS").map(|v| !v.is_empty()).unwrap_or(false)
+}
+
+pub fn configs_from_env() -> Result<Vec<FeedConfig>, Box<Error>> {
+    let contents = std::fs::read_to_string(var("RYMC_FEEDS")?)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Summaries are usually HTML (and occasionally the whole post), so this flattens them
+/// down to a line or two of plain text.
+fn plain_summary(html: &str) -> String {
+    let text = escape::decode_html_entities(&HTML_TAG_REGEX.replace_all(html, " "));
+    let text = WHITESPACE_REGEX.replace_all(&text, " ").trim().to_string();
+
+    match text.chars().count() > SUMMARY_LENGTH {
+        true => format!("{}…", text.chars().take(SUMMARY_LENGTH).collect::<String>().trim_end()),
+        false => text
+    }
+}
+
+/// Fills in the template in a single pass, so a title that happens to contain `{url}`
+/// doesn't get expanded in turn.
+fn render(config: &FeedConfig, feed_title: &str, title: &str, url: &str, summary: &str) -> String {
+    PLACEHOLDER_REGEX.replace_all(&config.template, |captures: &Captures| {
+        match &captures[1] {
+            "title" => escape::markdown_text(title),
+            "url" => escape::markdown_url(url),
+            "summary" => escape::markdown_text(summary),
+            _ => escape::markdown_text(feed_title)
+        }
+    }).trim().to_string()
+}
+
+/// An entry's own page: its `alternate` link (which is what a link without a `rel` is,
+/// too), rather than, say, its comments or an enclosure. Failing that, whatever it links first.
+fn entry_url(links: &[feed_rs::model::Link]) -> String {
+    links.iter().find(|link| matches!(link.rel.as_deref(), None | Some("alternate")))
+        .or_else(|| links.first())
+        .map(|link| link.href.clone())
+        .unwrap_or_default()
+}
+
+fn fetch_feed(config: &FeedConfig) -> Result<Vec<Activity>, Box<Error>> {
+    let mut response: Vec<u8> = vec![];
+    reqwest::get(&config.url)?.read_to_end(&mut response)?;
+    let feed = feed_rs::parser::parse(&response[..])?;
+    let feed_title = feed.title.map(|title| title.content).unwrap_or_default();
+
+    // Undated entries can't be placed in the feed, so they don't count towards the limit.
+    let dated = feed.entries.into_iter().filter_map(|entry| {
+        entry.published.or(entry.updated).map(|ts| (ts.naive_utc(), entry))
+    }).take(config.limit);
+
+    let mut activities: Vec<Activity> = vec![];
+    for (ts, entry) in dated {
+        let url = entry_url(&entry.links);
+        let title = entry.title.map(|title| plain_summary(&title.content)).unwrap_or_default();
+        let summary = entry.summary.map(|summary| plain_summary(&summary.content)).unwrap_or_default();
+
+        activities.push(Activity::new(&config.activity_type, render(config, &feed_title, &title, &url, &summary), DateTime {
+            action: config.action.clone(),
+            url: url,
+            ts: ts
+        }));
+    }
+
+    Ok(activities)
+}
+
+/// Fetches every configured feed. One feed failing doesn't take the rest down with it.
+pub fn get_and_transform_feeds_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let mut activities: Vec<Activity> = vec![];
+
+    for config in configs_from_env()? {
+        match fetch_feed(&config) {
+            Ok(mut items) => { activities.append(&mut items); },
+            Err(e) => { eprintln!("Error fetching feed {}: {:?}", config.url, e); }
+        }
+    }
+
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use crate::testing::serve;
+
+    fn config(url: &str, template: &str, limit: usize) -> FeedConfig {
+        FeedConfig {
+            url: format!("{}/feed", url),
+            activity_type: "blog".into(),
+            action: "Wrote".into(),
+            template: template.into(),
+            limit: limit
+        }
+    }
+
+    fn contents(activities: &[Activity]) -> Vec<&str> {
+        activities.iter().map(|activity| activity.content.as_str()).collect()
+    }
+
+    #[test]
+    fn renders_rss() {
+        let (url, _) = serve(vec![include_str!("../fixtures/feeds/rss.xml").into()]);
+        let activities = fetch_feed(&config(&url, "{feed}: [{title}]({url})\n\n{summary}", 2)).unwrap();
+
+        // The undated draft is skipped before the limit is applied, so it doesn't use one up.
+        assert_eq!(contents(&activities), vec![
+            "Ryan's Blog & Notes: [Templates with {url} in them](https://rymc.io/blog/2019/templates/)\n\n\
+             Some bold & \\[bracketed\\] text",
+            "Ryan's Blog & Notes: [Second post](https://rymc.io/blog/2019/second/)\n\nMore"
+        ]);
+
+        assert_eq!(activities[0].activity_type, "blog");
+        assert_eq!(activities[0].datetime.action, "Wrote");
+        assert_eq!(activities[0].datetime.url, "https://rymc.io/blog/2019/templates/");
+        assert_eq!(activities[0].datetime.ts, NaiveDateTime::parse_from_str("2019-01-10 10:00", "%Y-%m-%d %H:%M").unwrap());
+    }
+
+    #[test]
+    fn renders_atom() {
+        let (url, _) = serve(vec![include_str!("../fixtures/feeds/atom.xml").into()]);
+        let activities = fetch_feed(&config(&url, "[{title}]({url})", 5)).unwrap();
+
+        assert_eq!(contents(&activities), vec![
+            "[Finished \\*Dune\\* (again)](https://rymc.io/reading/dune_%281965%29)",
+            "[Started something](https://rymc.io/reading/next)"
+        ]);
+
+        // Entries with only an `updated` date use that; otherwise `published` wins.
+        assert_eq!(activities[0].datetime.ts, NaiveDateTime::parse_from_str("2019-01-09 10:00", "%Y-%m-%d %H:%M").unwrap());
+        assert_eq!(activities[1].datetime.ts, NaiveDateTime::parse_from_str("2019-01-05 10:00", "%Y-%m-%d %H:%M").unwrap());
+    }
+
+    #[test]
+    fn renders_json_feed() {
+        let (url, _) = serve(vec![include_str!("../fixtures/feeds/feed.json").into()]);
+        let activities = fetch_feed(&config(&url, "[{title}]({url}) - {summary}", 5)).unwrap();
+
+        assert_eq!(contents(&activities), vec!["[Links, & \\[more\\]](https://rymc.io/links/2) - Worth"]);
+        assert_eq!(activities[0].datetime.url, "https://rymc.io/links/2");
+    }
+}
@@ -0,0 +1,326 @@
+//! gists.rs
+//!
+//! Gists, as a GitHub sub-source. `GistEvent` was retired from the events API, so
+//! anything published only as a gist never shows up in the GitHub feed; this goes
+//! and asks for them directly instead.
+//!
+//! This is optional, and only runs if `RYMC_GITHUB_GISTS` is `true`.
+//!
+//! GitHub bumps a gist's `updated_at` for stars and comments as well as edits, so
+//! that can't say whether it changed. Its files' `raw_url`s can, as they point at a
+//! specific revision; those (and the excerpt we rendered from them) get remembered
+//! in `gists-state.json` between runs.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserializer, Deserialize, Serializer};
+use chrono::NaiveDateTime;
+
+use crate::{Activity, DateTime, escape};
+use crate::env::flag_from_env;
+use crate::github::Account;
+use crate::state::load_json;
+
+const EXCERPT_LINES: usize = 5;
+const GISTS_PER_PAGE: usize = 100;
+
+#[derive(Deserialize, Debug)]
+pub struct GistFile {
+    pub filename: String,
+    pub language: Option<String>,
+    pub raw_url: String,
+
+    #[serde(default)]
+    pub size: u64
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Gist {
+    pub id: String,
+    pub html_url: String,
+    pub description: Option<String>,
+
+    pub files: BTreeMap<String, GistFile>,
+
+    #[serde(deserialize_with = "deserialize_gist_timestamp")]
+    pub created_at: NaiveDateTime,
+
+    #[serde(deserialize_with = "deserialize_gist_timestamp")]
+    pub updated_at: NaiveDateTime
+}
+
+/// What we remember about a gist: which revision we last saw, the excerpt we rendered
+/// from it, and when we last saw it change (if we ever have).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GistState {
+    pub revision: String,
+    pub excerpt: String,
+
+    #[serde(default, serialize_with = "serialize_optional_timestamp", deserialize_with = "deserialize_optional_timestamp")]
+    pub updated_at: Option<NaiveDateTime>
+}
+
+const FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+fn serialize_optional_timestamp<S>(dt: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    match dt {
+        Some(dt) => serializer.serialize_str(&dt.format(FORMAT).to_string()),
+        None => serializer.serialize_none()
+    }
+}
+
+fn deserialize_optional_timestamp<'de, D>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error> where D: Deserializer<'de> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => NaiveDateTime::parse_from_str(&s, FORMAT).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None)
+    }
+}
+
+fn deserialize_gist_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    NaiveDateTime::parse_from_str(&s, FORMAT).map_err(serde::de::Error::custom)
+}
+
+pub fn enabled() -> bool {
+    flag_from_env("RYMC_GITHUB_GISTS")
+}
+
+fn state_path(path: &str) -> String {
+    format!("{}/gists-state.json", path)
+}
+
+/// Reads what we remembered last time: for each account (by profile URL), each gist (by
+/// URL).
+fn load_state(path: &str) -> Result<HashMap<String, HashMap<String, GistState>>, Box<Error>> {
+    load_json(&state_path(path))
+}
+
+/// The file a gist gets shown as: the biggest one, since that's usually the point of
+/// it (rather than, say, a README). Ties go to whichever sorts first.
+fn primary_file(gist: &Gist) -> Option<&GistFile> {
+    gist.files.values().fold(None, |primary: Option<&GistFile>, file| match primary {
+        Some(primary) if primary.size >= file.size => Some(primary),
+        _ => Some(file)
+    })
+}
+
+/// Each file's `raw_url` pins down a revision, so between them they say which one the
+/// gist is at.
+fn revision(gist: &Gist) -> String {
+    gist.files.values().map(|file| file.raw_url.as_str()).collect::<Vec<&str>>().join(" ")
+}
+
+/// Fetches every public gist for the user, walking through all the pages the API hands back.
+fn fetch_gists(account: &Account) -> Result<Vec<Gist>, Box<Error>> {
+    let mut gists: Vec<Gist> = vec![];
+
+    for page in 1.. {
+        let endpoint = format!(
+            "{}/users/{}/gists?per_page={}&page={}",
+            account.api_url, account.username, GISTS_PER_PAGE, page
+        );
+
+        let mut batch: Vec<Gist> = account.get(&endpoint)?.json()?;
+        let is_last_page = batch.len() < GISTS_PER_PAGE;
+        gists.append(&mut batch);
+
+        if is_last_page {
+            break;
+        }
+    }
+
+    Ok(gists)
+}
+
+/// The first few lines of `file`, fenced off as code. The fence is always longer than
+/// any run of backticks in the code itself, so nothing can break out of it.
+fn code_excerpt(file: &GistFile) -> Result<String, Box<Error>> {
+    let code = reqwest::get(&file.raw_url)?.text()?;
+    let lines: Vec<&str> = code.lines().take(EXCERPT_LINES).collect();
+    let excerpt = lines.join("\n");
+
+    let longest_run = excerpt.split(|c| c != '`').map(|run| run.len()).max().unwrap_or(0);
+    let fence = "`".repeat(std::cmp::max(3, longest_run + 1));
+    let language = file.language.clone().unwrap_or_default().to_lowercase();
+
+    Ok(format!("{}{}\n{}\n{}", fence, language, excerpt, fence))
+}
+
+fn render(gist: &Gist, file: &GistFile, excerpt: &str, verb: &str) -> String {
+    let description = match gist.description.as_ref().map(|d| d.trim()) {
+        Some(d) if !d.is_empty() => d.to_string(),
+        _ => file.filename.clone()
+    };
+
+    let language = match &file.language {
+        Some(language) => format!(" ({})", language),
+        None => "".into()
+    };
+
+    format!(
+        "{} a gist: [{}]({} \"View {} on GitHub\"){}\n\n{}",
+        verb, escape::markdown_text(&description), gist.html_url, escape::markdown_link_title(&description),
+        language, excerpt
+    )
+}
+
+/// Fetches `account`'s public gists, as "Published a gist" activities - plus an "Updated
+/// a gist" one for anything we've seen change since it went up. Only gists that are new
+/// or have changed get their contents fetched; everything else reuses its last excerpt.
+pub fn get_and_transform_gists_to_html(account: &Account, path: &str) -> Result<Vec<Activity>, Box<Error>> {
+    let gists = fetch_gists(account)?;
+    let mut states = load_state(path)?;
+    let mut state = states.remove(&account.web(&account.username)).unwrap_or_default();
+    let mut seen: HashMap<String, GistState> = HashMap::new();
+
+    let mut activities: Vec<Activity> = vec![];
+    for gist in gists {
+        let file = match primary_file(&gist) {
+            Some(file) => file,
+            None => continue
+        };
+
+        let revision = revision(&gist);
+        let known = match state.remove(&gist.html_url) {
+            Some(known) if known.revision == revision => known,
+
+            previous => {
+                let excerpt = match code_excerpt(file) {
+                    Ok(excerpt) => excerpt,
+                    Err(e) => {
+                        eprintln!("Error fetching gist {}: {:?}", gist.id, e);
+                        if let Some(previous) = previous {
+                            seen.insert(gist.html_url.clone(), previous);
+                        }
+
+                        continue;
+                    }
+                };
+
+                // A gist we've never seen is new (or new to us), not updated.
+                let updated_at = previous.map(|_| gist.updated_at);
+                GistState { revision: revision, excerpt: excerpt, updated_at: updated_at }
+            }
+        };
+
+        activities.push(Activity::new("github", render(&gist, file, &known.excerpt, "Published"), DateTime {
+            action: "Published".into(),
+            url: gist.html_url.clone(),
+            ts: gist.created_at
+        }).with_id(format!("{}#published", gist.html_url)));
+
+        if let Some(updated_at) = known.updated_at {
+            activities.push(Activity::new("github", render(&gist, file, &known.excerpt, "Updated"), DateTime {
+                action: "Updated".into(),
+                url: gist.html_url.clone(),
+                ts: updated_at
+            }).with_id(format!("{}#updated-{}", gist.html_url, updated_at.and_utc().timestamp())));
+        }
+
+        seen.insert(gist.html_url.clone(), known);
+    }
+
+    // Gists that have since been deleted get forgotten along the way.
+    states.insert(account.web(&account.username), seen);
+    std::fs::write(state_path(path), serde_json::to_string(&states)?)?;
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{account, paths, serve_with};
+
+    fn gists(server: &str, revision: &str, updated_at: &str) -> String {
+        serde_json::json!([{
+            "id": "abc123",
+            "html_url": "https://gist.github.com/abc123",
+            "description": "Grouping pushes",
+            "files": {
+                "README.md": {
+                    "filename": "README.md",
+                    "language": "Markdown",
+                    "raw_url": format!("{}/raw/{}/README.md", server, revision),
+                    "size": 40
+                },
+                "group.rs": {
+                    "filename": "group.rs",
+                    "language": "Rust",
+                    "raw_url": format!("{}/raw/{}/group.rs", server, revision),
+                    "size": 900
+                }
+            },
+            "created_at": "2019-01-01T10:00:00Z",
+            "updated_at": updated_at
+        }]).to_string()
+    }
+
+    #[test]
+    fn only_fetches_and_updates_gists_that_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let (url, requests) = serve_with(|url| vec![
+            gists(url, "r1", "2019-01-01T10:00:00Z"), "fn group() {}".into(),
+            gists(url, "r1", "2019-02-01T10:00:00Z"),
+            gists(url, "r2", "2019-03-01T10:00:00Z"), "fn group(pushes: &[Push]) {}".into(),
+            gists(url, "r2", "2019-03-01T10:00:00Z")
+        ]);
+
+        let account = account(&url);
+
+        // First time around: it's new, so it's published (from its biggest file), not updated.
+        let activities = get_and_transform_gists_to_html(&account, path).unwrap();
+        let first = requests.recv().unwrap();
+        assert_eq!(first.line, "GET /users/ryanmcgrath/gists?per_page=100&page=1 HTTP/1.1");
+        assert_eq!(first.header("authorization"), Some("token token"));
+        assert_eq!(paths(&requests), vec!["/raw/r1/group.rs"]);
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].datetime.action, "Published");
+        assert!(activities[0].content.contains("(Rust)\n\n```rust\nfn group() {}\n```"));
+
+        // Starred, which bumps `updated_at` but not the revision: nothing new to fetch or say.
+        let activities = get_and_transform_gists_to_html(&account, path).unwrap();
+        assert_eq!(paths(&requests).len(), 1);
+        assert_eq!(activities.len(), 1);
+        assert!(activities[0].content.contains("fn group() {}"));
+
+        // Edited: the new revision gets fetched, and it's an update from here on out.
+        for fetches in &[2, 1] {
+            let activities = get_and_transform_gists_to_html(&account, path).unwrap();
+            assert_eq!(paths(&requests).len(), *fetches);
+            assert_eq!(activities.len(), 2);
+            assert_eq!(activities[1].datetime.action, "Updated");
+            assert_eq!(activities[1].datetime.ts, NaiveDateTime::parse_from_str("2019-03-01T10:00:00Z", FORMAT).unwrap());
+            assert!(activities[1].content.contains("fn group(pushes: &[Push]) {}"));
+        }
+    }
+
+    #[test]
+    fn walks_every_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let (url, requests) = serve_with(|url| {
+            let page: Vec<serde_json::Value> = (0..GISTS_PER_PAGE).map(|i| serde_json::json!({
+                "id": format!("{}", i),
+                "html_url": format!("https://gist.github.com/{}", i),
+                "description": null,
+                "files": {},
+                "created_at": "2019-01-01T10:00:00Z",
+                "updated_at": "2019-01-01T10:00:00Z"
+            })).collect();
+
+            vec![serde_json::to_string(&page).unwrap(), gists(url, "r1", "2019-01-01T10:00:00Z"), "fn group() {}".into()]
+        });
+
+        let activities = get_and_transform_gists_to_html(&account(&url), path).unwrap();
+        let paths = paths(&requests);
+        assert_eq!(paths[1], "/users/ryanmcgrath/gists?per_page=100&page=2");
+        assert_eq!(paths.len(), 3);
+        assert_eq!(activities.len(), 1);
+    }
+}
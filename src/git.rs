@@ -0,0 +1,258 @@
+//! git.rs
+//!
+//! Commits from local git repositories, read straight out of `git log`. Plenty of
+//! work happens in private or self-hosted repos that never make it into GitHub's
+//! public events; this picks it up from wherever it's checked out instead.
+//!
+//! `RYMC_GIT_REPOS` lists repositories (or directories full of them), and
+//! `RYMC_GIT_AUTHORS` the emails whose commits count. With no authors, nothing
+//! gets published - most checkouts are full of other people's work.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use chrono::NaiveDateTime;
+
+use crate::{Activity, DateTime, escape};
+use crate::env::list_from_env;
+
+const COMMITS_PER_REPO: usize = 20;
+
+// Unit and record separators, so subjects can contain whatever they like.
+const FORMAT: &'static str = "--format=%H%x1f%ct%x1f%s%x1e";
+
+#[derive(Debug)]
+pub struct Commit {
+    pub sha: String,
+    pub subject: String,
+    pub ts: NaiveDateTime
+}
+
+/// Only runs if there are repos configured.
+pub fn enabled() -> bool {
+    var("RYMC_GIT_REPOS").map(|v| !v.is_empty()).unwrap_or(false)
+}
+
+/// Repos (bare or not) report their git directory relative to themselves; a directory
+/// that just sits somewhere inside one gets a path out to its parent's instead.
+fn is_repo(path: &Path) -> bool {
+    match git(path, &["rev-parse", "--git-dir"]) {
+        Ok(dir) => dir.trim() == ".git" || dir.trim() == ".",
+        Err(_) => false
+    }
+}
+
+/// Every configured path that's a repo, plus every repo directly inside the ones that
+/// aren't. Paths that can't be read get logged and skipped.
+fn repos(paths: &[String]) -> Vec<PathBuf> {
+    let mut repos: Vec<PathBuf> = vec![];
+
+    for path in paths {
+        let path = PathBuf::from(path);
+        if is_repo(&path) {
+            repos.push(path);
+            continue;
+        }
+
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) => { eprintln!("Error reading git repos in {}: {:?}", path.display(), e); continue; }
+        };
+
+        for entry in entries.flatten() {
+            let entry = entry.path();
+            if entry.is_dir() && is_repo(&entry) {
+                repos.push(entry);
+            }
+        }
+    }
+
+    repos.sort();
+    repos
+}
+
+fn git(repo: &Path, args: &[&str]) -> Result<String, Box<Error>> {
+    let output = Command::new("git").arg("-C").arg(repo).args(args).output()?;
+
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into())
+    }
+}
+
+/// The latest commits by any of `authors`. `--author` matches anywhere in `Name <email>`,
+/// so each email is matched with its brackets, as a fixed string.
+fn commits(repo: &Path, authors: &[String]) -> Result<Vec<Commit>, Box<Error>> {
+    let limit = format!("-n{}", COMMITS_PER_REPO);
+    let authors: Vec<String> = authors.iter().map(|email| format!("--author=<{}>", email)).collect();
+
+    let mut args = vec!["log", "--no-merges", "--fixed-strings", "--regexp-ignore-case", &limit, FORMAT];
+    args.extend(authors.iter().map(|author| author.as_str()));
+    let log = git(repo, &args)?;
+
+    Ok(log.split('\u{1e}').filter_map(|record| {
+        let fields: Vec<&str> = record.trim_start_matches('\n').split('\u{1f}').collect();
+        match fields.as_slice() {
+            [sha, ts, subject] => Some(Commit {
+                sha: sha.to_string(),
+                subject: subject.to_string(),
+                ts: chrono::DateTime::from_timestamp(ts.parse().ok()?, 0)?.naive_utc()
+            }),
+
+            _ => None
+        }
+    }).collect())
+}
+
+/// Turns `origin`'s URL into a web URL, if it looks like something with one - e.g,
+/// `git@github.com:owner/repo.git` or `ssh://git@git.example.com/owner/repo` both
+/// become `https://<host>/owner/repo`.
+fn web_url(remote: &str) -> Option<String> {
+    let remote = remote.trim().trim_end_matches("/").trim_end_matches(".git");
+
+    let (host, path) = if remote.starts_with("https://") || remote.starts_with("http://") {
+        let (_, rest) = remote.split_once("://")?;
+        let mut parts = rest.splitn(2, '/');
+        (parts.next()?.to_string(), parts.next()?.to_string())
+    } else if remote.starts_with("ssh://") {
+        let rest = remote.trim_start_matches("ssh://");
+        let mut parts = rest.splitn(2, '/');
+        let host = parts.next()?.rsplit('@').next()?.split(':').next()?.to_string();
+        (host, parts.next()?.to_string())
+    } else if remote.contains(':') && !remote.contains("://") {
+        let mut parts = remote.splitn(2, ':');
+        (parts.next()?.rsplit('@').next()?.to_string(), parts.next()?.to_string())
+    } else {
+        return None;
+    };
+
+    // Credentials in an HTTPS remote have no business ending up on the page.
+    let host = host.rsplit('@').next()?.to_string();
+    Some(format!("https://{}/{}", host, path))
+}
+
+/// The repo's name: `owner/repo` off the remote, if there is one, or the directory name if not.
+fn repo_name(repo: &Path, url: Option<&String>) -> String {
+    match url.and_then(|url| url.splitn(4, '/').nth(3)) {
+        Some(path) => path.to_string(),
+        None => repo.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+}
+
+fn render(commit: &Commit, name: &str, url: Option<&String>) -> String {
+    match url {
+        Some(url) => format!(
+            "Committed [{}]({}/commit/{} \"View this commit\") to [{}]({} \"View {}\")",
+            escape::markdown_text(&commit.subject), escape::markdown_url(url), commit.sha,
+            escape::markdown_text(name), escape::markdown_url(url), escape::markdown_link_title(name)
+        ),
+
+        None => format!("Committed {} to {}", escape::markdown_text(&commit.subject), escape::markdown_text(name))
+    }
+}
+
+/// Reads commits by the configured authors out of every configured repo. A repo that
+/// can't be read just gets skipped.
+pub fn get_and_transform_commits_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let authors = list_from_env("RYMC_GIT_AUTHORS");
+    if authors.is_empty() {
+        eprintln!("RYMC_GIT_REPOS is set but RYMC_GIT_AUTHORS isn't; skipping local git commits.");
+        return Ok(vec![]);
+    }
+
+    Ok(commits_to_activities(&list_from_env("RYMC_GIT_REPOS"), &authors))
+}
+
+fn commits_to_activities(paths: &[String], authors: &[String]) -> Vec<Activity> {
+    let mut activities: Vec<Activity> = vec![];
+    for repo in repos(paths) {
+        let commits = match commits(&repo, authors) {
+            Ok(commits) => commits,
+            Err(e) => { eprintln!("Error reading git log for {}: {:?}", repo.display(), e); continue; }
+        };
+
+        let url = git(&repo, &["config", "--get", "remote.origin.url"]).ok().and_then(|remote| web_url(&remote));
+        let name = repo_name(&repo, url.as_ref());
+
+        for commit in commits {
+            activities.push(Activity::new("git", render(&commit, &name, url.as_ref()), DateTime {
+                action: "Committed".into(),
+                url: url.as_ref().map(|url| format!("{}/commit/{}", url, commit.sha)).unwrap_or_default(),
+                ts: commit.ts
+            }));
+        }
+    }
+
+    activities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn run(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").arg("-C").arg(dir).args(args).output().unwrap().status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn commit(repo: &Path, email: &str, subject: &str) {
+        let email = format!("user.email={}", email);
+        run(repo, &["-c", "user.name=Someone", "-c", &email, "-c", "commit.gpgsign=false", "commit", "-q", "--allow-empty", "-m", subject]);
+    }
+
+    /// A directory holding a repo with commits from two people, and a bare clone of it.
+    fn fixture() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let repo = dir.path().join("project");
+        std::fs::create_dir(&repo).unwrap();
+
+        run(&repo, &["init", "-q"]);
+        commit(&repo, "ryan@rymc.io", "Mine");
+        commit(&repo, "bryan@rymc.io", "Not mine");
+        commit(&repo, "someone@example.com", "Theirs");
+        commit(&repo, "Ryan@RYMC.io", "Also mine");
+
+        run(dir.path(), &["clone", "-q", "--bare", "project", "project.git"]);
+        dir
+    }
+
+    #[test]
+    fn finds_repos_bare_or_not() {
+        let dir = fixture();
+        let root = dir.path().to_string_lossy().into_owned();
+        let project = dir.path().join("project").to_string_lossy().into_owned();
+
+        assert!(is_repo(&dir.path().join("project")));
+        assert!(is_repo(&dir.path().join("project.git")));
+        assert!(!is_repo(dir.path()));
+
+        assert_eq!(repos(&[root]), vec![dir.path().join("project"), dir.path().join("project.git")]);
+        assert_eq!(repos(std::slice::from_ref(&project)), vec![PathBuf::from(project)]);
+    }
+
+    #[test]
+    fn only_reads_the_configured_authors() {
+        let dir = fixture();
+        let commits = commits(&dir.path().join("project"), &["ryan@rymc.io".into()]).unwrap();
+        let subjects: Vec<&str> = commits.iter().map(|commit| commit.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["Also mine", "Mine"]);
+    }
+
+    #[test]
+    fn skips_paths_that_cannot_be_read() {
+        let dir = fixture();
+        let paths = vec![
+            dir.path().join("missing").to_string_lossy().into_owned(),
+            dir.path().join("project").to_string_lossy().into_owned()
+        ];
+
+        let activities = commits_to_activities(&paths, &["someone@example.com".into()]);
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].content, "Committed Theirs to project");
+    }
+}
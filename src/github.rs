@@ -5,17 +5,25 @@
 //! @author Ryan McGrath <ryan@rymc.io>
 //! @copyright RYMC 2019
 
-use std::{env::var, error::Error, fmt};
+use std::{collections::{BTreeMap, HashMap}, env::var, error::Error, fmt};
 use serde::{Deserializer, Deserialize};
 use chrono::NaiveDateTime;
-use linkify::LinkFinder;
-use regex::Regex;
+use regex::{Captures, Regex};
 
-use crate::{Activity, DateTime, markdown_link_title_escape};
+use crate::{Activity, DateTime, comments, contributions, escape, gists, grouping};
+use crate::env::{flag_from_env, list_from_env};
 
 lazy_static! {
-    static ref SOCIAL_MENTION_REGEX: Regex = Regex::new(r"(@[\w_-]+)").unwrap();
-    static ref SOCIAL_HASHTAG_REGEX: Regex = Regex::new(r"(@[\w_-]+)").unwrap();
+    // Picks up @mentions, issue/PR references and commit SHAs the same way github.com
+    // autolinks them. Only ever run over plain prose; see `linkable_ranges`.
+    static ref GITHUB_REFERENCE_REGEX: Regex = Regex::new(concat!(
+        r"(?P<pre>^|[^\w/@#.-])(?:",
+            r"@(?P<mention>[\w-]+)",
+            r"|(?:(?P<issue_repo>[\w.-]+/[\w.-]+))?#(?P<issue>\d+)\b",
+            r"|GH-(?P<gh_issue>\d+)\b",
+            r"|(?:(?P<commit_repo>[\w.-]+/[\w.-]+)@)?(?P<sha>[0-9a-f]{7,40})\b",
+        r")"
+    )).unwrap();
 }
 
 #[derive(Debug)]
@@ -44,7 +52,7 @@ fn get(value: &serde_json::Value, path: &str) -> Result<String, Box<Error>> {
     let mut v = value;
 
     for key in keys {
-        v = v.get(&key).ok_or_else(|| GHKeyError {
+        v = v.get(&key).ok_or(GHKeyError {
             keypath: key
         })?;
     }
@@ -62,8 +70,335 @@ fn deserialize_github_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime
 
 #[derive(Deserialize, Debug)]
 pub struct Repository {
-    name: String,
-    url: String
+    name: String
+}
+
+/// A GitHub account to pull activity from. `api_url` and `web_url` default to github.com,
+/// but can point at a GitHub Enterprise Server instance instead (e.g,
+/// `https://github.example.com/api/v3` and `https://github.example.com`).
+#[derive(Debug)]
+pub struct Account {
+    pub username: String,
+    pub access_token: String,
+    pub api_url: String,
+    pub web_url: String
+}
+
+impl Account {
+    /// Reads an account from `<prefix>_USERNAME`, `<prefix>_ACCESS_TOKEN`, `<prefix>_API_URL`
+    /// and `<prefix>_WEB_URL`. The token is required, and so is the username unless there's
+    /// a `default_username` to fall back on.
+    pub fn from_env(prefix: &str, default_username: Option<&str>) -> Result<Self, Box<Error>> {
+        let setting = |key: &str| {
+            var(format!("{}_{}", prefix, key)).ok().map(|v| v.trim_end_matches('/').to_string()).filter(|v| !v.is_empty())
+        };
+
+        Ok(Account {
+            access_token: var(format!("{}_ACCESS_TOKEN", prefix)).map_err(|_| format!("{}_ACCESS_TOKEN not set", prefix))?,
+            username: setting("USERNAME").or_else(|| default_username.map(|u| u.to_string()))
+                .ok_or_else(|| format!("{}_USERNAME not set", prefix))?,
+            api_url: setting("API_URL").unwrap_or_else(|| "https://api.github.com".into()),
+            web_url: setting("WEB_URL").unwrap_or_else(|| "https://github.com".into())
+        })
+    }
+
+    /// The main account (`RYMC_GITHUB_*`), followed by any extras listed in
+    /// `RYMC_GITHUB_ACCOUNTS` - e.g, `WORK` reads from `RYMC_GITHUB_WORK_*`. Extras have to
+    /// say whose activity they're for; only the main account assumes it's ours.
+    pub fn all_from_env() -> Vec<Account> {
+        let mut accounts = vec![Account::from_env("RYMC_GITHUB", Some("ryanmcgrath")).expect("GITHUB_ACCESS_TOKEN not set!")];
+
+        for name in var("RYMC_GITHUB_ACCOUNTS").unwrap_or_default().split(",").map(|n| n.trim()).filter(|n| !n.is_empty()) {
+            match Account::from_env(&format!("RYMC_GITHUB_{}", name.to_uppercase()), None) {
+                Ok(account) => accounts.push(account),
+                Err(e) => { eprintln!("Skipping GitHub account {}: {}", name, e); }
+            }
+        }
+
+        accounts
+    }
+
+    /// A link to `path` (e.g, `owner/repo`) on this account's GitHub.
+    pub fn web(&self, path: &str) -> String {
+        format!("{}/{}", self.web_url, path)
+    }
+
+    /// The GraphQL endpoint, which on Enterprise lives next to (not under) the REST API.
+    pub fn graphql_url(&self) -> String {
+        match self.api_url.ends_with("/v3") {
+            true => format!("{}/graphql", self.api_url.trim_end_matches("/v3")),
+            false => format!("{}/graphql", self.api_url)
+        }
+    }
+
+    /// GETs `endpoint` as this account. The token goes in a header, since GitHub no
+    /// longer accepts it as an `access_token` query parameter.
+    pub fn get(&self, endpoint: &str) -> reqwest::Result<reqwest::Response> {
+        reqwest::Client::new().get(endpoint)
+            .header("Authorization", format!("token {}", self.access_token))
+            .header("User-Agent", "activity-scraper")
+            .send()
+    }
+}
+
+/// A repository as the Code tab sees it. This is what gets written to `github-repos.json`
+/// (as an array, most recently pushed first), and the field names/types here are the
+/// contract with the site - don't change them without updating the site too.
+///
+/// `pushed_at` is an ISO 8601 UTC timestamp (`2019-01-01T00:00:00Z`), or `null` for a
+/// repository that's never had anything pushed to it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RepoCard {
+    pub name: String,
+    pub full_name: String,
+    pub html_url: String,
+    pub description: Option<String>,
+    pub language: Option<String>,
+
+    #[serde(rename(deserialize = "stargazers_count"))]
+    pub stars: i64,
+
+    #[serde(rename(deserialize = "forks_count"))]
+    pub forks: i64,
+
+    #[serde(default)]
+    pub topics: Vec<String>,
+
+    pub pushed_at: Option<String>,
+    pub archived: bool,
+    pub fork: bool
+}
+
+/// Which repositories make it into `github-repos.json`. Set `RYMC_GITHUB_HIDE_FORKS`
+/// and/or `RYMC_GITHUB_HIDE_ARCHIVED` to `true` to leave those out.
+#[derive(Debug)]
+pub struct RepoFilter {
+    pub hide_forks: bool,
+    pub hide_archived: bool
+}
+
+impl RepoFilter {
+    pub fn from_env() -> Self {
+        RepoFilter {
+            hide_forks: flag_from_env("RYMC_GITHUB_HIDE_FORKS"),
+            hide_archived: flag_from_env("RYMC_GITHUB_HIDE_ARCHIVED")
+        }
+    }
+
+    pub fn allows(&self, repo: &RepoCard) -> bool {
+        (!self.hide_forks || !repo.fork) && (!self.hide_archived || !repo.archived)
+    }
+}
+
+/// Matches `s` against a simple glob, where `*` matches anything (slashes included) and
+/// `?` matches any single character.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    // matches[j]: does the pattern so far match the first j characters of `s`?
+    let mut matches = vec![false; s.len() + 1];
+    matches[0] = true;
+
+    for p in pattern {
+        let previous = matches.clone();
+
+        match p {
+            '*' => for j in 1..=s.len() {
+                matches[j] = previous[j] || matches[j - 1];
+            },
+
+            _ => {
+                matches[0] = false;
+                for j in 1..=s.len() {
+                    matches[j] = previous[j - 1] && (p == '?' || p == s[j - 1]);
+                }
+            }
+        }
+    }
+
+    matches[s.len()]
+}
+
+/// Allow/deny lists for which GitHub events make it into the feed, read from the
+/// environment as comma separated lists:
+///
+/// - `RYMC_GITHUB_ALLOW_REPOS` / `RYMC_GITHUB_DENY_REPOS`: repo globs (e.g, `myorg/*`),
+///   matched regardless of case.
+/// - `RYMC_GITHUB_ALLOW_ORGS` / `RYMC_GITHUB_DENY_ORGS`: repo owners.
+/// - `RYMC_GITHUB_ALLOW_EVENTS` / `RYMC_GITHUB_DENY_EVENTS`: event types (e.g, `ForkEvent`).
+/// - `RYMC_GITHUB_ALLOW_BRANCHES` / `RYMC_GITHUB_DENY_BRANCHES`: branch globs for pushes
+///   (e.g, `gh-pages,dependabot/*`).
+///
+/// An empty allow list allows everything; deny lists always win.
+#[derive(Debug)]
+pub struct EventFilter {
+    pub allow_repos: Vec<String>,
+    pub deny_repos: Vec<String>,
+    pub allow_orgs: Vec<String>,
+    pub deny_orgs: Vec<String>,
+    pub allow_events: Vec<String>,
+    pub deny_events: Vec<String>,
+    pub allow_branches: Vec<String>,
+    pub deny_branches: Vec<String>
+}
+
+impl EventFilter {
+    pub fn from_env() -> Self {
+        EventFilter {
+            allow_repos: list_from_env("RYMC_GITHUB_ALLOW_REPOS"),
+            deny_repos: list_from_env("RYMC_GITHUB_DENY_REPOS"),
+            allow_orgs: list_from_env("RYMC_GITHUB_ALLOW_ORGS"),
+            deny_orgs: list_from_env("RYMC_GITHUB_DENY_ORGS"),
+            allow_events: list_from_env("RYMC_GITHUB_ALLOW_EVENTS"),
+            deny_events: list_from_env("RYMC_GITHUB_DENY_EVENTS"),
+            allow_branches: list_from_env("RYMC_GITHUB_ALLOW_BRANCHES"),
+            deny_branches: list_from_env("RYMC_GITHUB_DENY_BRANCHES")
+        }
+    }
+
+    /// Checks `activity` against the lists, returning what it got filtered by (if anything).
+    pub fn rejects(&self, activity: &GitHubActivity) -> Option<&'static str> {
+        let permits = |allow: &[String], deny: &[String], value: &str, matcher: &Fn(&str, &str) -> bool| {
+            !deny.iter().any(|d| matcher(d, value)) && (allow.is_empty() || allow.iter().any(|a| matcher(a, value)))
+        };
+
+        // Owner and repo names are case insensitive on GitHub; branch names aren't.
+        let equals = |a: &str, b: &str| a.eq_ignore_ascii_case(b);
+        let repo_match = |pattern: &str, repo: &str| glob_match(&pattern.to_lowercase(), &repo.to_lowercase());
+        let repo = &activity.repo.name;
+        let org = repo.split("/").next().unwrap_or("");
+
+        if !permits(&self.allow_repos, &self.deny_repos, repo, &repo_match) {
+            return Some("repo");
+        }
+
+        if !permits(&self.allow_orgs, &self.deny_orgs, org, &equals) {
+            return Some("org");
+        }
+
+        if !permits(&self.allow_events, &self.deny_events, &activity.action, &equals) {
+            return Some("event type");
+        }
+
+        if activity.action == "PushEvent" {
+            if let Ok(reference) = get(&activity.payload, "ref") {
+                let branch = reference.trim_start_matches("refs/heads/");
+                if !permits(&self.allow_branches, &self.deny_branches, branch, &glob_match) {
+                    return Some("branch");
+                }
+            }
+        }
+
+        None
+    }
+}
+
+const REPOS_PER_PAGE: usize = 100;
+
+/// Fetches every public repository for the user, walking through all the pages the API
+/// hands back.
+pub fn fetch_repositories(account: &Account) -> Result<Vec<RepoCard>, Box<Error>> {
+    let mut repositories: Vec<RepoCard> = vec![];
+
+    for page in 1.. {
+        let endpoint = format!(
+            "{}/users/{}/repos?sort=pushed&per_page={}&page={}",
+            account.api_url, account.username, REPOS_PER_PAGE, page
+        );
+
+        let mut batch: Vec<RepoCard> = account.get(&endpoint)?.json()?;
+        let is_last_page = batch.len() < REPOS_PER_PAGE;
+        repositories.append(&mut batch);
+
+        if is_last_page {
+            break;
+        }
+    }
+
+    Ok(repositories)
+}
+
+/// Fetches and filters the repositories, and writes them out to `github-repos.json`.
+fn write_repositories(account: &Account, filter: &RepoFilter, path: &str) -> Result<Vec<RepoCard>, Box<Error>> {
+    let repositories: Vec<RepoCard> = fetch_repositories(account)?.into_iter().filter(|repo| {
+        filter.allows(repo)
+    }).collect();
+
+    let contents = serde_json::to_string(&repositories)?;
+    std::fs::write(format!("{}/github-repos.json", path), contents).expect("Could not write GitHub repositories to file!");
+    Ok(repositories)
+}
+
+#[derive(Serialize, Debug)]
+pub struct LanguageStat {
+    pub name: String,
+    pub bytes: i64
+}
+
+#[derive(Serialize, Debug)]
+pub struct TopicStat {
+    pub name: String,
+    pub count: i64
+}
+
+/// Aggregate numbers across the repositories on the Code tab, written to
+/// `github-stats.json` so the profile page doesn't have to crunch them itself.
+#[derive(Serialize, Debug)]
+pub struct RepoStats {
+    pub languages: Vec<LanguageStat>,
+    pub topics: Vec<TopicStat>,
+    pub total_stars: i64,
+    pub total_forks: i64,
+    pub recently_active: Vec<RepoCard>
+}
+
+const RECENTLY_ACTIVE_COUNT: usize = 5;
+
+/// Tallies up stats for `repositories`. Language breakdowns come from the per-repo
+/// languages endpoint; a repo that fails to load just gets skipped.
+pub fn compute_repo_stats(repositories: &[RepoCard], account: &Account) -> RepoStats {
+    let mut languages: HashMap<String, i64> = HashMap::new();
+    let mut topics: HashMap<String, i64> = HashMap::new();
+
+    for repo in repositories {
+        let endpoint = format!("{}/repos/{}/languages", account.api_url, repo.full_name);
+
+        match account.get(&endpoint).and_then(|mut r| r.json::<HashMap<String, i64>>()) {
+            Ok(breakdown) => for (language, bytes) in breakdown {
+                *languages.entry(language).or_insert(0) += bytes;
+            },
+
+            Err(e) => { eprintln!("Error fetching languages for {}: {:?}", repo.full_name, e); }
+        }
+
+        for topic in repo.topics.iter() {
+            *topics.entry(topic.clone()).or_insert(0) += 1;
+        }
+    }
+
+    let mut languages: Vec<LanguageStat> = languages.into_iter().map(|(name, bytes)| {
+        LanguageStat { name: name, bytes: bytes }
+    }).collect();
+    languages.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+
+    let mut topics: Vec<TopicStat> = topics.into_iter().map(|(name, count)| {
+        TopicStat { name: name, count: count }
+    }).collect();
+    topics.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+
+    // `pushed_at` is ISO 8601, so it sorts correctly as a plain string.
+    let mut recently_active: Vec<RepoCard> = repositories.iter().filter(|repo| repo.pushed_at.is_some()).cloned().collect();
+    recently_active.sort_by(|a, b| b.pushed_at.cmp(&a.pushed_at));
+    recently_active.truncate(RECENTLY_ACTIVE_COUNT);
+
+    RepoStats {
+        languages: languages,
+        topics: topics,
+        total_stars: repositories.iter().map(|repo| repo.stars).sum(),
+        total_forks: repositories.iter().map(|repo| repo.forks).sum(),
+        recently_active: recently_active
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -78,60 +413,68 @@ pub struct GitHubActivity {
     pub created_at: NaiveDateTime
 }
 
-fn clean_text(s: &str) -> String {
-    let cut: Vec<String> = s.to_string().split("\n\n> On").map(|x| {
-        x.to_owned()
-    }).collect();
-    
-    let mut text = cut[0].to_string();
-        
-    let link_finder = LinkFinder::new();
-    let links: Vec<_> = link_finder.links(s).collect();
-
-    // For each link, markdown-ify it
-    for link in links {
-        // If this exists, it's high chances it's already markdown
-        // e.g, ](http...
-        let start = link.start() - 2;
-        if start > 0 && text.chars().nth(start) == Some(']') {
-            continue;
+/// Links @mentions, issue/PR references and commit SHAs the way github.com does. Short
+/// references resolve against `repo`, and links point at `web_url` (e.g, `https://github.com`).
+fn link_references(s: &str, repo: &str, web_url: &str) -> String {
+    GITHUB_REFERENCE_REGEX.replace_all(s, |captures: &Captures| {
+        let pre = captures.name("pre").map(|m| m.as_str()).unwrap_or("");
+
+        if let Some(mention) = captures.name("mention") {
+            let user = mention.as_str();
+            return format!("{}[@{}]({}/{})", pre, user, web_url, user);
         }
 
-        let l = link.as_str();
-        text = text.replace(link.as_str(), &format!("[{}]({})", l, l));
-    }
-    
-    let mentions: Vec<String> = SOCIAL_MENTION_REGEX.captures_iter(&text).map(|capture| {
-        capture.get(0).unwrap().as_str().to_owned()
-    }).collect();
-    
-    for mention in mentions {
-        text = text.replace(&mention, &format!(
-            "[{}]({})", mention,
-            format!("https://github.com/{}", mention.replace("@", ""))
-        ));
-    }
+        if let Some(issue) = captures.name("issue") {
+            let target = captures.name("issue_repo").map(|m| m.as_str()).unwrap_or(repo);
+            let label = comments::issue_label(&format!("#{}", issue.as_str()), target, repo);
 
-    let hashtags: Vec<String> = SOCIAL_HASHTAG_REGEX.captures_iter(&text).map(|capture| {
-        capture.get(0).unwrap().as_str().to_owned()
-    }).collect();
-    
-    // A hashtag in GitHub refers to an issue, potentially in another repo!
-    //for hashtag in hashtags {
-        /*text = text.replace(&hashtag, &format!(
-            "[{}]({})", hashtag,
-            format!("https://github.com/{}", hashtag.replace("#", ""))
-        ));*/
-    //}
-
-    text
+            return format!("{}[{}]({}/{}/issues/{})", pre, label, web_url, target, issue.as_str());
+        }
+
+        if let Some(issue) = captures.name("gh_issue") {
+            return format!("{}[GH-{}]({}/{}/issues/{})", pre, issue.as_str(), web_url, repo, issue.as_str());
+        }
+
+        let sha = captures.name("sha").unwrap().as_str();
+        let target = captures.name("commit_repo").map(|m| m.as_str()).unwrap_or(repo);
+        let label = match comments::commit_label(sha, target, repo) {
+            Some(label) => label,
+            None => return captures.get(0).unwrap().as_str().to_string()
+        };
+
+        format!("{}[`{}`]({}/{}/commit/{})", pre, label, web_url, target, sha)
+    }).into_owned()
 }
 
-fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match activity.action.as_ref() {
+fn clean_text(s: &str, repo: &str, web_url: &str) -> String {
+    comments::clean_text(s, &|s: &str| link_references(s, repo, web_url))
+}
+
+fn render_comment(body: &str, html_url: &str, repo: &str, web_url: &str) -> String {
+    comments::render_comment(body, html_url, "GitHub", &|s: &str| link_references(s, repo, web_url))
+}
+
+/// The full, cleaned body of a comment event, for consumers that want more than the
+/// excerpt in `content`.
+fn comment_body(activity: &GitHubActivity, account: &Account) -> Option<String> {
+    match activity.action.as_ref() {
+        "CommitCommentEvent" | "IssueCommentEvent" => {
+            get(&activity.payload, "comment.body").ok().map(|body| clean_text(&body, &activity.repo.name, &account.web_url))
+        },
+
+        _ => None
+    }
+}
+
+fn patch_text(activity: &GitHubActivity, account: &Account) -> Result<String, Box<Error>> { match activity.action.as_ref() {
     "CommitCommentEvent" => { Ok(format!(
         "{} on [{}]({} \"View {} on GitHub\")",
-        clean_text(&get(&activity.payload, "comment.body")?),
-        activity.repo.name, activity.repo.url, markdown_link_title_escape(&activity.repo.name)
+        render_comment(
+            &get(&activity.payload, "comment.body")?,
+            &get(&activity.payload, "comment.html_url")?,
+            &activity.repo.name, &account.web_url
+        ),
+        activity.repo.name, account.web(&activity.repo.name), escape::markdown_link_title(&activity.repo.name)
     ))},
     
     "IssueCommentEvent" => {
@@ -143,9 +486,9 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
 
         Ok(format!(
             "{} on [{}]({} \"View {} on GitHub\")",
-            clean_text(&body), title,
+            render_comment(&body, &get(&activity.payload, "comment.html_url")?, &activity.repo.name, &account.web_url), escape::markdown_text(&title),
             get(&activity.payload, "issue.html_url")?,
-            markdown_link_title_escape(&title)
+            escape::markdown_link_title(&title)
         ))
     },
     
@@ -153,11 +496,11 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
         let full_name = get(&activity.payload, "forkee.full_name")?;
 
         Ok(format!(
-            "Forked [@{}](https://github.com/{} \"View {} on GitHub\") to [@{}]({} \"View {} on GitHub\")",
-            activity.repo.name, activity.repo.name, activity.repo.name,
+            "Forked [@{}]({} \"View {} on GitHub\") to [@{}]({} \"View {} on GitHub\")",
+            activity.repo.name, account.web(&activity.repo.name), activity.repo.name,
             full_name,
             get(&activity.payload, "forkee.html_url")?,
-            markdown_link_title_escape(&full_name)
+            escape::markdown_link_title(&full_name)
         ))
     },
 
@@ -166,8 +509,8 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
             let full_name = &activity.repo.name;
 
             Ok(format!(
-                "Created [@{}](https://github.com/{} \"View {} on GitHub\")",
-                full_name, full_name, markdown_link_title_escape(&full_name)
+                "Created [@{}]({} \"View {} on GitHub\")",
+                full_name, account.web(full_name), escape::markdown_link_title(full_name)
             ))
         },
 
@@ -181,9 +524,9 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
             let repo = get(&activity.payload, "repository.full_name")?;
 
             Ok(format!(
-                "Opened [{}]({} \"View {} on GitHub\") in [@{}](https://github.com/{} \"View {} on GitHub\")",
-                title, get(&activity.payload, "issue.html_url")?,
-                markdown_link_title_escape(&title), repo, repo, markdown_link_title_escape(&repo)
+                "Opened [{}]({} \"View {} on GitHub\") in [@{}]({} \"View {} on GitHub\")",
+                escape::markdown_text(&title), get(&activity.payload, "issue.html_url")?,
+                escape::markdown_link_title(&title), repo, account.web(&repo), escape::markdown_link_title(&repo)
             ))
         },
         
@@ -192,9 +535,9 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
             let repo = &activity.repo.name;
 
             Ok(format!(
-                "Closed [{}]({} \"View {} on GitHub\") in [@{}](https://github.com/{} \"View {} on GitHub\")",
-                title, get(&activity.payload, "issue.html_url")?,
-                markdown_link_title_escape(&title), repo, repo, markdown_link_title_escape(&repo)
+                "Closed [{}]({} \"View {} on GitHub\") in [@{}]({} \"View {} on GitHub\")",
+                escape::markdown_text(&title), get(&activity.payload, "issue.html_url")?,
+                escape::markdown_link_title(&title), repo, account.web(repo), escape::markdown_link_title(repo)
             ))
         },
 
@@ -206,9 +549,9 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
             let full_name = get(&activity.payload, "pull_request.base.repo.full_name")?;
 
             Ok(format!(
-                "Opened a pull request in [@{}](https://github.com/{} \"View {} on GitHub\"):\n\n[{}]({} \"View this PR on GitHub\")",
-                full_name, full_name, full_name,
-                get(&activity.payload, "pull_request.title")?,
+                "Opened a pull request in [@{}]({} \"View {} on GitHub\"):\n\n[{}]({} \"View this PR on GitHub\")",
+                full_name, account.web(&full_name), full_name,
+                escape::markdown_text(&get(&activity.payload, "pull_request.title")?),
                 get(&activity.payload, "pull_request.html_url")?
             ))
         },
@@ -217,9 +560,9 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
             let full_name = get(&activity.payload, "pull_request.base.repo.full_name")?;
             
             Ok(format!(
-                "Closed a pull request in [@{}](https://github.com/{} \"View {} on GitHub\"):\n\n[{}]({} \"View this PR on GitHub\")",
-                full_name, full_name, full_name,
-                get(&activity.payload, "pull_request.title")?,
+                "Closed a pull request in [@{}]({} \"View {} on GitHub\"):\n\n[{}]({} \"View this PR on GitHub\")",
+                full_name, account.web(&full_name), full_name,
+                escape::markdown_text(&get(&activity.payload, "pull_request.title")?),
                 get(&activity.payload, "pull_request.html_url")?
             ))
         },
@@ -235,19 +578,19 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
         })?;
 
         let compare_url = format!(
-            "https://github.com/{}/compare/{}...{}",
-            activity.repo.name,
+            "{}/compare/{}...{}",
+            account.web(&activity.repo.name),
             get(&activity.payload, "before")?,
             get(&activity.payload, "head")?
         );
 
         Ok(format!(
-            "Pushed [{} commit{}]({} \"View these changes on GitHub\") to [@{}](https://github.com/{} \"View {} on GitHub\")",
+            "Pushed [{} commit{}]({} \"View these changes on GitHub\") to [@{}]({} \"View {} on GitHub\")",
             no, match no {
                 1 => "",
                 _ => "s"
             }, compare_url, activity.repo.name,
-            activity.repo.name, markdown_link_title_escape(&activity.repo.name)
+            account.web(&activity.repo.name), escape::markdown_link_title(&activity.repo.name)
         ))
     },
     
@@ -255,8 +598,8 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
         let full_name = get(&activity.payload, "repository.full_name")?;
 
         Ok(format!(
-            "Open sourced [@{}](https://github.com/{} \"View {} on GitHub\")",
-            full_name, full_name, markdown_link_title_escape(&full_name)
+            "Open sourced [@{}]({} \"View {} on GitHub\")",
+            full_name, account.web(&full_name), escape::markdown_link_title(&full_name)
         ))
     },
         
@@ -264,7 +607,7 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
         Ok(format!(
             "Released [@{} {}]({} \"View this release on GitHub\")",
             get(&activity.payload, "repository.full_name")?,
-            get(&activity.payload, "release.tag_name")?,
+            escape::markdown_text(&get(&activity.payload, "release.tag_name")?),
             get(&activity.payload, "release.html_url")?
         ))
     },
@@ -272,33 +615,474 @@ fn patch_text(activity: &GitHubActivity) -> Result<String, Box<Error>> { match a
     uncaught => GHKeyError::raise(uncaught)
 }}
 
-pub fn get_and_transform_activity_to_html() -> Result<Vec<Activity>, Box<Error>> {
-    let access_token = var("RYMC_GITHUB_ACCESS_TOKEN").expect("GITHUB_ACCESS_TOKEN not set!");
+/// Fetches and renders public activity for a single account, leaving out anything
+/// `filter` rejects.
+fn fetch_activity(account: &Account, filter: &EventFilter, rules: &[grouping::Rule]) -> Result<Vec<Activity>, Box<Error>> {
+    let activities_endpoint = format!("{}/users/{}/events/public", account.api_url, account.username);
+    let events: Vec<serde_json::Value> = account.get(&activities_endpoint)?.json()?;
 
-    // Fetch the repositories, which the Code tab uses for UI. Then we'll grab activity to render
-    // in the sidebar.
-    let repositories_endpoint = format!("https://api.github.com/users/ryanmcgrath/repos?access_token={}&sort=pushed", access_token);
-    let repositories = reqwest::get(&repositories_endpoint)?.text()?;
-    let path = std::env::var("RYMC_ACTIVITY_PATH").expect("Activity feed filepath not set!");
-    std::fs::write(&format!("{}/github-repos.json", path), repositories).expect("Could not write Dribbble shots to file!");
+    let mut activities: Vec<Activity> = vec![];
+    let mut skipped: BTreeMap<&'static str, usize> = BTreeMap::new();
 
-    // Now we can do our normal thing - fetch activity and render Markdown/etc.
-    let activities_endpoint = format!("https://api.github.com/users/ryanmcgrath/events/public?access_token={}", access_token);
-    let github_activities: Vec<GitHubActivity> = reqwest::get(&activities_endpoint)?.json()?;
+    for event in events {
+        let activity: GitHubActivity = match serde_json::from_value(event.clone()) {
+            Ok(a) => a,
+            Err(e) => { eprintln!("{}", e); continue; }
+        };
 
-    let mut activities: Vec<Activity> = vec![];
-    for activity in github_activities {
-        let content = match patch_text(&activity) {
+        if let Some(reason) = filter.rejects(&activity) {
+            *skipped.entry(reason).or_insert(0) += 1;
+            continue;
+        }
+
+        let content = match patch_text(&activity, account) {
             Ok(c) => c,
             Err(e) => { eprintln!("{}", e); continue; }
         };
 
+        let id = format!("{}/events/{}", account.web_url, event["id"].as_str().unwrap_or_default());
         activities.push(Activity::new("github", content, DateTime {
             action: "On".into(),
             url: "".into(),
             ts: activity.created_at
-        }));
+        }).with_id(id).with_source(event).with_body(comment_body(&activity, account)));
+    }
+
+    if !skipped.is_empty() {
+        let reasons: Vec<String> = skipped.iter().map(|(reason, no)| format!("{} by {}", no, reason)).collect();
+        eprintln!(
+            "Filtered out {} GitHub events for {} ({})",
+            skipped.values().sum::<usize>(), account.username, reasons.join(", ")
+        );
+    }
+
+    Ok(grouping::group_activities(activities, rules, &account.web_url))
+}
+
+pub fn get_and_transform_activity_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let accounts = Account::all_from_env();
+    let primary = &accounts[0];
+
+    // A typo in the grouping rules should stop us before we've fetched anything, not after.
+    let rules = grouping::rules_from_env()?;
+
+    // Fetch the repositories, which the Code tab uses for UI. Then we'll grab activity to render
+    // in the sidebar. The Code tab (and friends) only ever reflect the main account.
+    let path = std::env::var("RYMC_ACTIVITY_PATH").expect("Activity feed filepath not set!");
+    let repositories = write_repositories(primary, &RepoFilter::from_env(), &path)?;
+
+    let stats = compute_repo_stats(&repositories, primary);
+    let contents = serde_json::to_string(&stats)?;
+    std::fs::write(format!("{}/github-stats.json", path), contents).expect("Could not write GitHub stats to file!");
+
+    if contributions::enabled() {
+        let endpoint = var("RYMC_GITHUB_GRAPHQL_URL").unwrap_or_else(|_| primary.graphql_url());
+        match contributions::fetch(&endpoint, &primary.username, &primary.access_token) {
+            Ok(calendar) => {
+                let contents = serde_json::to_string(&calendar)?;
+                std::fs::write(format!("{}/github-contributions.json", path), contents).expect("Could not write GitHub contributions to file!");
+            },
+
+            Err(e) => { eprintln!("Error fetching GitHub contributions: {:?}", e); }
+        }
+    }
+
+    // Now we can do our normal thing - fetch activity and render Markdown/etc.
+    let filter = EventFilter::from_env();
+    let mut activities: Vec<Activity> = vec![];
+    for account in accounts.iter() {
+        match fetch_activity(account, &filter, &rules) {
+            Ok(mut a) => { activities.append(&mut a); },
+            Err(e) => { eprintln!("Error fetching GitHub activity for {} ({}): {:?}", account.username, account.web_url, e); }
+        }
+
+        if gists::enabled() {
+            match gists::get_and_transform_gists_to_html(account, &path) {
+                Ok(mut a) => { activities.append(&mut a); },
+                Err(e) => { eprintln!("Error fetching gists for {} ({}): {:?}", account.username, account.web_url, e); }
+            }
+        }
     }
 
     Ok(activities)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{account, paths, serve};
+    use proptest::prelude::*;
+
+    const REPO: &str = "ryanmcgrath/social";
+    const WEB_URL: &str = "https://github.com";
+
+    fn clean(s: &str) -> String {
+        clean_text(s, REPO, WEB_URL)
+    }
+
+    /// Bits of comment that clean_text has opinions about, to build inputs out of.
+    fn token() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-zA-Z0-9]{1,8}".prop_map(|s| s),
+            Just(" ".to_string()),
+            Just("\n".to_string()),
+            Just("\n\n".to_string()),
+            Just("@ryanmcgrath".to_string()),
+            Just("#12".to_string()),
+            Just("GH-34".to_string()),
+            Just("ryanmcgrath/other#56".to_string()),
+            Just("a1b2c3d4e5f6".to_string()),
+            Just("https://rymc.io/".to_string()),
+            Just(":tada:".to_string()),
+            Just("- [ ] ".to_string()),
+            Just("<b>".to_string()),
+            Just("*".to_string()),
+            Just("_".to_string()),
+            Just("`".to_string()),
+            Just("\\".to_string()),
+            Just("[".to_string()),
+            Just("](".to_string()),
+            Just(")".to_string()),
+            Just("|".to_string()),
+            Just("> ".to_string()),
+            Just("&amp;".to_string()),
+            Just("é".to_string()),
+            Just("👩‍💻".to_string()),
+            Just("🇯🇵".to_string())
+        ]
+    }
+
+    fn markdownish() -> impl Strategy<Value = String> {
+        prop::collection::vec(token(), 0..40).prop_map(|tokens| tokens.concat())
+    }
+
+    /// What code can contain without ending the span or fence it's in.
+    fn code() -> impl Strategy<Value = String> {
+        prop::collection::vec(prop_oneof![
+            "[a-z0-9]{1,6}".prop_map(|s| s),
+            Just(" ".to_string()),
+            Just("@ryanmcgrath".to_string()),
+            Just("#12".to_string()),
+            Just("GH-34".to_string()),
+            Just("a1b2c3d4e5f6".to_string()),
+            Just("https://rymc.io/".to_string()),
+            Just(":tada:".to_string()),
+            Just("<b>".to_string()),
+            Just("\\".to_string()),
+            Just("*".to_string())
+        ], 1..12).prop_map(|tokens| tokens.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics_on_arbitrary_text(s in any::<String>()) {
+            clean(&s);
+        }
+
+        #[test]
+        fn never_panics_on_markdown(s in markdownish()) {
+            clean(&s);
+        }
+
+        #[test]
+        fn leaves_code_spans_alone(before in "[a-z ]{0,10}", code in code(), after in "[a-z ]{0,10}") {
+            let code = code.trim().to_string();
+            prop_assume!(!code.is_empty());
+
+            let span = format!("`{}`", code);
+            let output = clean(&format!("{} {} {}", before, span, after));
+            prop_assert!(output.contains(&span), "{:?} lost {:?}", output, span);
+        }
+
+        #[test]
+        fn leaves_code_fences_alone(lines in prop::collection::vec(code(), 1..4)) {
+            let fence = format!("```\n{}\n```", lines.join("\n"));
+            let output = clean(&format!("Look:\n\n{}\n\nThanks", fence));
+            prop_assert!(output.contains(&fence), "{:?} lost {:?}", output, fence);
+        }
+
+        #[test]
+        fn keeps_escapes_as_written(
+            parts in prop::collection::vec((
+                prop::sample::select(vec!['\\', '`', '*', '_', '[', ']', '#', '<', '>', '!', '|', '@', ':']),
+                "[a-z0-9]{0,6}"
+            ), 1..8)
+        ) {
+            let escapes: Vec<String> = parts.iter().map(|(c, word)| format!("\\{}{}", c, word)).collect();
+            let input = escapes.join(" ");
+            let output = clean(&input);
+
+            prop_assert_eq!(output.matches('\\').count(), input.matches('\\').count(), "{:?} -> {:?}", input, output);
+            for escape in &escapes {
+                let escaped = escape.chars().take(2).collect::<String>();
+                prop_assert!(output.contains(&escaped), "{:?} -> {:?}", input, output);
+            }
+        }
+    }
+
+    #[test]
+    fn tells_escaped_backslashes_from_escapes() {
+        assert_eq!(clean("a \\#12 b"), "a \\#12 b");
+        assert_eq!(clean("a \\\\#12 b"), "a \\\\[#12](https://github.com/ryanmcgrath/social/issues/12) b");
+    }
+
+    #[test]
+    fn links_references() {
+        assert_eq!(
+            clean("Fixed in a1b2c3d4e5f6, see #12 and @ryanmcgrath"),
+            "Fixed in [`a1b2c3d`](https://github.com/ryanmcgrath/social/commit/a1b2c3d4e5f6), see [#12](https://github.com/ryanmcgrath/social/issues/12) and [@ryanmcgrath](https://github.com/ryanmcgrath)"
+        );
+    }
+
+    fn link(s: &str) -> String {
+        link_references(s, REPO, WEB_URL)
+    }
+
+    #[test]
+    fn links_gh_references_to_this_repo() {
+        assert_eq!(link("Dupe of GH-34."), "Dupe of [GH-34](https://github.com/ryanmcgrath/social/issues/34).");
+        assert_eq!(link("ryanmcgrath/other#5"), "[ryanmcgrath/other#5](https://github.com/ryanmcgrath/other/issues/5)");
+    }
+
+    #[test]
+    fn leaves_emails_alone() {
+        assert_eq!(link("Mail ryan@rymc.io or foo@bar"), "Mail ryan@rymc.io or foo@bar");
+        assert_eq!(link("(@ryanmcgrath)"), "([@ryanmcgrath](https://github.com/ryanmcgrath))");
+    }
+
+    #[test]
+    fn leaves_hex_looking_words_alone() {
+        assert_eq!(link("deadbeef facade1 1234567 decade"), "deadbeef facade1 1234567 decade");
+        assert_eq!(link("a1b2c3dz"), "a1b2c3dz");
+        assert_eq!(
+            link("Reverted 3f2a9c1."),
+            "Reverted [`3f2a9c1`](https://github.com/ryanmcgrath/social/commit/3f2a9c1)."
+        );
+    }
+
+    #[test]
+    fn keeps_byte_offsets_straight_after_multibyte_text() {
+        assert_eq!(
+            clean("👩‍💻 é \\# 🇯🇵 #12"),
+            "👩‍💻 é \\# 🇯🇵 [#12](https://github.com/ryanmcgrath/social/issues/12)"
+        );
+    }
+
+    #[test]
+    fn flattens_tables_inside_blockquotes() {
+        assert_eq!(
+            clean("> Results:\n>\n> | a | b |\n> |---|---|\n> | 1 | 2 |\n\nDone"),
+            "> Results:\n>\n> - a · b\n> - 1 · 2\n>\n\nDone"
+        );
+    }
+
+    #[test]
+    fn flattens_tables_inside_list_items() {
+        assert_eq!(
+            clean("- Results:\n\n  | a | b |\n  |---|---|\n  | 1 | 2 |\nAfter"),
+            "- Results:\n\n  - a · b\n  - 1 · 2\n\nAfter"
+        );
+    }
+
+    #[test]
+    fn drops_scripts_and_styles() {
+        assert_eq!(
+            clean("Hi\n\n<script>\nalert(\"#12\");\n</script>\n\nbye <STYLE>p { color: red; }</style> now<br>ok"),
+            "Hi\n\n\n\nbye  now  \nok"
+        );
+    }
+
+    // The shape GitHub hands back, extra fields and all.
+    fn api_repo(name: &str, fork: bool, archived: bool) -> serde_json::Value {
+        serde_json::json!({
+            "id": 1,
+            "name": name,
+            "full_name": format!("ryanmcgrath/{}", name),
+            "html_url": format!("https://github.com/ryanmcgrath/{}", name),
+            "description": null,
+            "language": "Rust",
+            "stargazers_count": 3,
+            "watchers_count": 3,
+            "forks_count": 1,
+            "topics": ["rust"],
+            "pushed_at": "2019-01-01T10:00:00Z",
+            "archived": archived,
+            "fork": fork
+        })
+    }
+
+    #[test]
+    fn writes_repositories_across_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let mut first: Vec<serde_json::Value> = (0..REPOS_PER_PAGE).map(|i| {
+            api_repo(&format!("filler-{}", i), true, false)
+        }).collect();
+
+        first[0] = serde_json::json!({
+            "id": 2,
+            "name": "social",
+            "full_name": "ryanmcgrath/social",
+            "html_url": "https://github.com/ryanmcgrath/social",
+            "description": "Activity feeds & <friends>",
+            "language": null,
+            "stargazers_count": 42,
+            "forks_count": 7,
+            "pushed_at": null,
+            "archived": false,
+            "fork": false
+        });
+
+        let second = vec![api_repo("twython", false, true)];
+        let (url, requests) = serve(vec![serde_json::to_string(&first).unwrap(), serde_json::to_string(&second).unwrap()]);
+
+        let filter = RepoFilter { hide_forks: true, hide_archived: false };
+        let repositories = write_repositories(&account(&url), &filter, path).unwrap();
+
+        // The token goes in a header, not the URL.
+        let first = requests.recv().unwrap();
+        assert_eq!(first.line, "GET /users/ryanmcgrath/repos?sort=pushed&per_page=100&page=1 HTTP/1.1");
+        assert_eq!(first.header("authorization"), Some("token token"));
+        assert_eq!(paths(&requests), vec!["/users/ryanmcgrath/repos?sort=pushed&per_page=100&page=2"]);
+
+        assert_eq!(repositories.len(), 2);
+        assert_eq!(std::fs::read_to_string(format!("{}/github-repos.json", path)).unwrap(), concat!(
+            r#"[{"name":"social","full_name":"ryanmcgrath/social","html_url":"https://github.com/ryanmcgrath/social","#,
+            r#""description":"Activity feeds & <friends>","language":null,"stars":42,"forks":7,"topics":[],"#,
+            r#""pushed_at":null,"archived":false,"fork":false},"#,
+            r#"{"name":"twython","full_name":"ryanmcgrath/twython","html_url":"https://github.com/ryanmcgrath/twython","#,
+            r#""description":null,"language":"Rust","stars":3,"forks":1,"topics":["rust"],"#,
+            r#""pushed_at":"2019-01-01T10:00:00Z","archived":true,"fork":false}]"#
+        ));
+    }
+
+    #[test]
+    fn filters_forks_and_archived_repositories() {
+        let repo = |fork: bool, archived: bool| -> RepoCard {
+            serde_json::from_value(api_repo("social", fork, archived)).unwrap()
+        };
+
+        let filters = [
+            (RepoFilter { hide_forks: false, hide_archived: false }, [true, true, true, true]),
+            (RepoFilter { hide_forks: true, hide_archived: false }, [true, false, true, false]),
+            (RepoFilter { hide_forks: false, hide_archived: true }, [true, true, false, false]),
+            (RepoFilter { hide_forks: true, hide_archived: true }, [true, false, false, false])
+        ];
+
+        for (filter, expected) in filters.iter() {
+            let allowed = [
+                filter.allows(&repo(false, false)),
+                filter.allows(&repo(true, false)),
+                filter.allows(&repo(false, true)),
+                filter.allows(&repo(true, true))
+            ];
+
+            assert_eq!(&allowed, expected, "{:?}", filter);
+        }
+    }
+
+    fn card(name: &str, topics: &[&str], pushed_at: Option<&str>) -> RepoCard {
+        RepoCard {
+            name: name.into(),
+            full_name: format!("ryanmcgrath/{}", name),
+            html_url: format!("https://github.com/ryanmcgrath/{}", name),
+            description: None,
+            language: None,
+            stars: 2,
+            forks: 1,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            pushed_at: pushed_at.map(|ts| ts.to_string()),
+            archived: false,
+            fork: false
+        }
+    }
+
+    #[test]
+    fn tallies_repository_stats() {
+        let repositories = vec![
+            card("a", &["rust", "cli"], Some("2019-01-01T00:00:00Z")),
+            card("b", &["rust"], Some("2019-01-07T00:00:00Z")),
+            card("c", &["web"], Some("2019-01-03T00:00:00Z")),
+            card("d", &[], None),
+            card("e", &["cli"], Some("2019-01-05T00:00:00Z")),
+            card("f", &[], Some("2019-01-02T00:00:00Z")),
+            card("g", &["web"], Some("2019-01-06T00:00:00Z"))
+        ];
+
+        let (url, requests) = serve(vec![
+            r#"{"Rust": 100, "Shell": 10}"#.into(),
+            // A languages call that fails shouldn't take the whole tally down with it.
+            "Not Found".into(),
+            r#"{"Rust": 50, "Go": 10}"#.into(),
+            "{}".into(),
+            r#"{"Ruby": 5}"#.into(),
+            r#"{"Ruby": 5}"#.into(),
+            "{}".into()
+        ]);
+
+        let stats = compute_repo_stats(&repositories, &account(&url));
+
+        let first = requests.recv().unwrap();
+        assert_eq!(first.line, "GET /repos/ryanmcgrath/a/languages HTTP/1.1");
+        assert_eq!(first.header("authorization"), Some("token token"));
+        assert_eq!(paths(&requests)[..1], ["/repos/ryanmcgrath/b/languages".to_string()]);
+
+        let languages: Vec<(&str, i64)> = stats.languages.iter().map(|l| (l.name.as_str(), l.bytes)).collect();
+        assert_eq!(languages, vec![("Rust", 150), ("Go", 10), ("Ruby", 10), ("Shell", 10)]);
+
+        let topics: Vec<(&str, i64)> = stats.topics.iter().map(|t| (t.name.as_str(), t.count)).collect();
+        assert_eq!(topics, vec![("cli", 2), ("rust", 2), ("web", 2)]);
+
+        assert_eq!(stats.total_stars, 14);
+        assert_eq!(stats.total_forks, 7);
+
+        let recent: Vec<&str> = stats.recently_active.iter().map(|repo| repo.name.as_str()).collect();
+        assert_eq!(recent, vec!["b", "g", "e", "c", "f"]);
+    }
+
+    #[test]
+    fn extra_accounts_need_a_username() {
+        std::env::set_var("RYMC_GITHUB_TEST_EXTRA_ACCESS_TOKEN", "token");
+        assert!(Account::from_env("RYMC_GITHUB_TEST_EXTRA", None).is_err());
+
+        let account = Account::from_env("RYMC_GITHUB_TEST_EXTRA", Some("ryanmcgrath")).unwrap();
+        assert_eq!(account.username, "ryanmcgrath");
+
+        std::env::set_var("RYMC_GITHUB_TEST_EXTRA_USERNAME", "rymc-work");
+        std::env::set_var("RYMC_GITHUB_TEST_EXTRA_WEB_URL", "https://github.example.com/");
+        let account = Account::from_env("RYMC_GITHUB_TEST_EXTRA", None).unwrap();
+        assert_eq!(account.username, "rymc-work");
+        assert_eq!(account.web_url, "https://github.example.com");
+        assert_eq!(account.api_url, "https://api.github.com");
+    }
+
+    fn push_to(repo: &str, branch: &str) -> GitHubActivity {
+        serde_json::from_value(serde_json::json!({
+            "type": "PushEvent",
+            "repo": { "name": repo },
+            "payload": { "ref": format!("refs/heads/{}", branch) },
+            "created_at": "2019-01-01T00:00:00Z"
+        })).unwrap()
+    }
+
+    #[test]
+    fn matches_repos_regardless_of_case() {
+        let filter = EventFilter {
+            allow_repos: vec![],
+            deny_repos: vec!["MyOrg/Experiments-*".into()],
+            allow_orgs: vec![],
+            deny_orgs: vec![],
+            allow_events: vec![],
+            deny_events: vec![],
+            allow_branches: vec![],
+            deny_branches: vec!["gh-pages".into()]
+        };
+
+        assert_eq!(filter.rejects(&push_to("myorg/experiments-one", "master")), Some("repo"));
+        assert_eq!(filter.rejects(&push_to("MYORG/EXPERIMENTS-TWO", "master")), Some("repo"));
+        assert_eq!(filter.rejects(&push_to("myorg/social", "master")), None);
+        assert_eq!(filter.rejects(&push_to("myorg/social", "gh-pages")), Some("branch"));
+        assert_eq!(filter.rejects(&push_to("myorg/social", "GH-Pages")), None);
+    }
+}
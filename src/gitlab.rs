@@ -0,0 +1,473 @@
+//! gitlab.rs
+//!
+//! Handles fetching and loading GitLab activity, from gitlab.com or a self-hosted
+//! instance. Events only come with a project ID, so projects get looked up (once
+//! each) to find out what they're called, where they live, and whether they're public.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use std::collections::HashMap;
+use regex::{Captures, Regex};
+use serde::{Deserializer, Deserialize};
+use chrono::NaiveDateTime;
+
+use crate::{Activity, DateTime, comments, escape};
+use crate::env::list_from_env;
+
+lazy_static! {
+    // GitLab's own reference syntax: @mentions, `#123` issues, `!123` merge requests and
+    // commit SHAs, each optionally qualified by a (possibly nested) project path.
+    static ref GITLAB_REFERENCE_REGEX: Regex = Regex::new(concat!(
+        r"(?P<pre>^|[^\w/@#!.-])(?:",
+            r"@(?P<mention>[\w-]+(?:\.[\w-]+)*)",
+            r"|(?:(?P<target_project>[\w.-]+(?:/[\w.-]+)+))?(?P<kind>[#!])(?P<number>\d+)\b",
+            r"|(?:(?P<commit_project>[\w.-]+(?:/[\w.-]+)+)@)?(?P<sha>[0-9a-f]{7,40})\b",
+        r")"
+    )).unwrap();
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PushData {
+    pub commit_count: i64,
+    pub action: String,
+    pub ref_type: String,
+    pub commit_from: Option<String>,
+    pub commit_to: Option<String>,
+
+    #[serde(rename = "ref")]
+    pub reference: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Note {
+    pub id: i64,
+    pub body: String,
+    pub noteable_type: String,
+    pub noteable_iid: Option<i64>,
+    pub commit_id: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Event {
+    pub id: i64,
+    pub project_id: Option<i64>,
+    pub action_name: String,
+    pub target_type: Option<String>,
+    pub target_iid: Option<i64>,
+    pub target_title: Option<String>,
+    pub push_data: Option<PushData>,
+    pub note: Option<Note>,
+
+    #[serde(deserialize_with = "deserialize_gitlab_timestamp")]
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Project {
+    pub path_with_namespace: String,
+    pub web_url: String,
+    pub visibility: Option<String>
+}
+
+impl Project {
+    /// Private and internal projects only show up if they're listed in
+    /// `RYMC_GITLAB_ALLOW_PROJECTS` (by path, e.g `group/project`).
+    pub fn is_publishable(&self, allowed: &[String]) -> bool {
+        self.visibility.as_deref() == Some("public") || allowed.iter().any(|project| {
+            project.eq_ignore_ascii_case(&self.path_with_namespace)
+        })
+    }
+}
+
+fn deserialize_gitlab_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    chrono::DateTime::parse_from_rfc3339(&s).map(|dt| dt.naive_utc()).map_err(serde::de::Error::custom)
+}
+
+/// Only runs if there's a token configured.
+pub fn enabled() -> bool {
+    var("RYMC_GITLAB_PRIVATE_TOKEN").map(|v| !v.is_empty()).unwrap_or(false)
+}
+
+/// Talks to the GitLab API, remembering every project it's looked up along the way.
+pub struct Client {
+    pub url: String,
+    token: String,
+    http: reqwest::Client,
+    projects: HashMap<i64, Project>
+}
+
+impl Client {
+    pub fn from_env() -> Result<Self, Box<Error>> {
+        Ok(Client {
+            url: var("RYMC_GITLAB_URL").unwrap_or_else(|_| "https://gitlab.com".into()).trim_end_matches('/').to_string(),
+            token: var("RYMC_GITLAB_PRIVATE_TOKEN")?,
+            http: reqwest::Client::new(),
+            projects: HashMap::new()
+        })
+    }
+
+    fn get<T>(&self, path: &str) -> Result<T, Box<Error>> where T: serde::de::DeserializeOwned {
+        let endpoint = format!("{}/api/v4/{}", self.url, path);
+        Ok(self.http.get(&endpoint).header("PRIVATE-TOKEN", self.token.as_str()).send()?.json()?)
+    }
+
+    pub fn project(&mut self, id: i64) -> Result<Project, Box<Error>> {
+        if let Some(project) = self.projects.get(&id) {
+            return Ok(project.clone());
+        }
+
+        let project: Project = self.get(&format!("projects/{}", id))?;
+        self.projects.insert(id, project.clone());
+        Ok(project)
+    }
+}
+
+fn project_link(project: &Project) -> String {
+    format!(
+        "[@{}]({} \"View {} on GitLab\")",
+        project.path_with_namespace, project.web_url, escape::markdown_link_title(&project.path_with_namespace)
+    )
+}
+
+/// Issues and MRs are found by `iid`, commits (which notes can be left on) by `sha`.
+fn target_url(project: &Project, target_type: &str, iid: Option<i64>, sha: Option<&str>) -> String {
+    match (target_type, iid, sha) {
+        ("Issue", Some(iid), _) => format!("{}/-/issues/{}", project.web_url, iid),
+        ("MergeRequest", Some(iid), _) => format!("{}/-/merge_requests/{}", project.web_url, iid),
+        ("Commit", _, Some(sha)) => format!("{}/-/commit/{}", project.web_url, sha),
+        _ => project.web_url.clone()
+    }
+}
+
+fn note_url(project: &Project, note: &Note) -> String {
+    target_url(project, &note.noteable_type, note.noteable_iid, note.commit_id.as_deref())
+}
+
+/// Links references the way GitLab does. Short references resolve against `project`
+/// (its path), and links point at `web_url` (e.g, `https://gitlab.com`).
+fn link_references(s: &str, project: &str, web_url: &str) -> String {
+    GITLAB_REFERENCE_REGEX.replace_all(s, |captures: &Captures| {
+        let pre = captures.name("pre").map(|m| m.as_str()).unwrap_or("");
+
+        if let Some(mention) = captures.name("mention") {
+            let user = mention.as_str();
+            return format!("{}[@{}]({}/{})", pre, user, web_url, user);
+        }
+
+        if let Some(number) = captures.name("number") {
+            let kind = &captures["kind"];
+            let path = match kind {
+                "!" => "merge_requests",
+                _ => "issues"
+            };
+
+            let target = captures.name("target_project").map(|m| m.as_str()).unwrap_or(project);
+            let label = comments::issue_label(&format!("{}{}", kind, number.as_str()), target, project);
+
+            return format!("{}[{}]({}/{}/-/{}/{})", pre, label, web_url, target, path, number.as_str());
+        }
+
+        let sha = captures.name("sha").unwrap().as_str();
+        let target = captures.name("commit_project").map(|m| m.as_str()).unwrap_or(project);
+        let label = match comments::commit_label(sha, target, project) {
+            Some(label) => label,
+            None => return captures.get(0).unwrap().as_str().to_string()
+        };
+
+        format!("{}[`{}`]({}/{}/-/commit/{})", pre, label, web_url, target, sha)
+    }).into_owned()
+}
+
+fn clean_text(s: &str, project: &Project, web_url: &str) -> String {
+    comments::clean_text(s, &|s: &str| link_references(s, &project.path_with_namespace, web_url))
+}
+
+/// Where an event lives: the note, the issue/MR, the pushed changes or tag, or failing
+/// all of those, the project.
+fn event_url(event: &Event, project: &Project) -> String {
+    if let Some(note) = &event.note {
+        return format!("{}#note_{}", note_url(project, note), note.id);
+    }
+
+    if let Some(push) = &event.push_data {
+        let reference = push.reference.clone().unwrap_or_default();
+
+        return match (push.ref_type.as_ref(), &push.commit_from, &push.commit_to) {
+            ("tag", _, _) => format!("{}/-/tags/{}", project.web_url, escape::markdown_url(&reference)),
+            (_, Some(from), Some(to)) => format!("{}/-/compare/{}...{}", project.web_url, from, to),
+            _ => format!("{}/-/commits/{}", project.web_url, escape::markdown_url(&reference))
+        };
+    }
+
+    match &event.target_type {
+        Some(target_type) => target_url(project, target_type, event.target_iid, None),
+        None => project.web_url.clone()
+    }
+}
+
+fn unhandled(event: &Event) -> Result<String, Box<Error>> {
+    Err(format!(
+        "Unhandled GitLab event ({} {}). Ignoring!",
+        event.action_name, event.target_type.as_deref().unwrap_or("-")
+    ).into())
+}
+
+fn patch_text(event: &Event, project: &Project, web_url: &str) -> Result<String, Box<Error>> {
+    let title = event.target_title.clone().unwrap_or_default();
+
+    if let Some(push) = &event.push_data {
+        let reference = push.reference.clone().unwrap_or_default();
+
+        return match (push.ref_type.as_ref(), push.action.as_ref()) {
+            ("tag", "created") => Ok(format!(
+                "Tagged [@{} {}]({} \"View this tag on GitLab\")",
+                project.path_with_namespace, escape::markdown_text(&reference), event_url(event, project)
+            )),
+
+            ("branch", "pushed") | ("branch", "created") => Ok(format!(
+                "Pushed [{} commit{}]({} \"View these changes on GitLab\") to {}",
+                push.commit_count, match push.commit_count {
+                    1 => "",
+                    _ => "s"
+                }, event_url(event, project), project_link(project)
+            )),
+
+            _ => unhandled(event)
+        };
+    }
+
+    match (event.target_type.as_deref(), event.action_name.as_ref()) {
+        (Some("MergeRequest"), action) if action == "opened" || action == "closed" || action == "merged" || action == "accepted" => {
+            let verb = match action {
+                "opened" => "Opened",
+                "closed" => "Closed",
+                _ => "Merged"
+            };
+
+            Ok(format!(
+                "{} a merge request in {}:\n\n[{}]({} \"View this MR on GitLab\")",
+                verb, project_link(project), escape::markdown_text(&title),
+                target_url(project, "MergeRequest", event.target_iid, None)
+            ))
+        },
+
+        (Some("Issue"), action) if action == "opened" || action == "closed" => Ok(format!(
+            "{} [{}]({} \"View {} on GitLab\") in {}",
+            match action {
+                "opened" => "Opened",
+                _ => "Closed"
+            },
+            escape::markdown_text(&title), target_url(project, "Issue", event.target_iid, None),
+            escape::markdown_link_title(&title), project_link(project)
+        )),
+
+        (Some("Note"), "commented on") | (Some("DiffNote"), "commented on") | (Some("DiscussionNote"), "commented on") => {
+            let note = match &event.note {
+                Some(note) => note,
+                None => return unhandled(event)
+            };
+
+            let url = note_url(project, note);
+            let references = |s: &str| link_references(s, &project.path_with_namespace, web_url);
+
+            Ok(format!(
+                "{} on [{}]({} \"View {} on GitLab\")",
+                comments::render_comment(&note.body, &event_url(event, project), "GitLab", &references),
+                escape::markdown_text(&title), url, escape::markdown_link_title(&title)
+            ))
+        },
+
+        _ => unhandled(event)
+    }
+}
+
+/// Calls out to GitLab for the configured user's events, then pushes them into a
+/// standard template that'll ultimately be rendered on the HTML side. Events in projects
+/// that aren't public get skipped, unless the project's explicitly allowed.
+pub fn get_and_transform_activity_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let client = Client::from_env()?;
+    let user = var("RYMC_GITLAB_USER_ID")?;
+    fetch(client, &user, &list_from_env("RYMC_GITLAB_ALLOW_PROJECTS"))
+}
+
+fn fetch(mut client: Client, user: &str, allowed: &[String]) -> Result<Vec<Activity>, Box<Error>> {
+    let events: Vec<Event> = client.get(&format!("users/{}/events?per_page=50", user))?;
+
+    let mut activities: Vec<Activity> = vec![];
+    for event in events {
+        let project = match event.project_id.map(|id| client.project(id)) {
+            Some(Ok(project)) => project,
+            Some(Err(e)) => { eprintln!("Error looking up GitLab project: {:?}", e); continue; },
+            None => continue
+        };
+
+        if !project.is_publishable(allowed) {
+            continue;
+        }
+
+        let content = match patch_text(&event, &project, &client.url) {
+            Ok(c) => c,
+            Err(e) => { eprintln!("{}", e); continue; }
+        };
+
+        let body = event.note.as_ref().map(|note| clean_text(&note.body, &project, &client.url));
+        let id = format!("{}/events/{}", client.url, event.id);
+
+        activities.push(Activity::new("gitlab", content, DateTime {
+            action: "On".into(),
+            url: event_url(&event, &project),
+            ts: event.created_at
+        }).with_id(id).with_body(body));
+    }
+
+    Ok(activities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::serve;
+
+    const PROJECT: &str = "ryanmcgrath/social";
+    const WEB_URL: &str = "https://gitlab.com";
+
+    #[test]
+    fn links_gitlab_references() {
+        assert_eq!(
+            link_references("See !12, #3 and group/sub/tool#4, thanks @ryan.mcgrath.", PROJECT, WEB_URL),
+            "See [!12](https://gitlab.com/ryanmcgrath/social/-/merge_requests/12), \
+             [#3](https://gitlab.com/ryanmcgrath/social/-/issues/3) and \
+             [group/sub/tool#4](https://gitlab.com/group/sub/tool/-/issues/4), \
+             thanks [@ryan.mcgrath](https://gitlab.com/ryan.mcgrath)."
+        );
+
+        assert_eq!(
+            link_references("Fixed in a1b2c3d4e5f6 and other/repo@b2c3d4e5f6a1", PROJECT, WEB_URL),
+            "Fixed in [`a1b2c3d`](https://gitlab.com/ryanmcgrath/social/-/commit/a1b2c3d4e5f6) and \
+             [`other/repo@b2c3d4e`](https://gitlab.com/other/repo/-/commit/b2c3d4e5f6a1)"
+        );
+    }
+
+    #[test]
+    fn leaves_github_references_alone() {
+        assert_eq!(link_references("GH-12", PROJECT, WEB_URL), "GH-12");
+    }
+
+    fn event(json: &str) -> Event {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn social() -> Project {
+        Project {
+            path_with_namespace: PROJECT.into(),
+            web_url: "https://gitlab.com/ryanmcgrath/social".into(),
+            visibility: Some("public".into())
+        }
+    }
+
+    #[test]
+    fn patches_tags_issues_and_merge_requests() {
+        let project = social();
+        let link = "[@ryanmcgrath/social](https://gitlab.com/ryanmcgrath/social \"View ryanmcgrath/social on GitLab\")";
+
+        let tag = event(r#"{
+            "id": 1, "project_id": 1, "action_name": "pushed new",
+            "push_data": { "commit_count": 0, "action": "created", "ref_type": "tag", "ref": "v1.0" },
+            "created_at": "2019-01-01T10:00:00.000Z"
+        }"#);
+        assert_eq!(
+            patch_text(&tag, &project, WEB_URL).unwrap(),
+            "Tagged [@ryanmcgrath/social v1.0](https://gitlab.com/ryanmcgrath/social/-/tags/v1.0 \"View this tag on GitLab\")"
+        );
+
+        for (action, verb) in &[("opened", "Opened"), ("closed", "Closed")] {
+            let issue = event(&format!(r#"{{
+                "id": 2, "project_id": 1, "action_name": "{}", "target_type": "Issue",
+                "target_iid": 3, "target_title": "Broken build",
+                "created_at": "2019-01-01T10:00:00.000Z"
+            }}"#, action));
+
+            assert_eq!(patch_text(&issue, &project, WEB_URL).unwrap(), format!(
+                "{} [Broken build](https://gitlab.com/ryanmcgrath/social/-/issues/3 \"View Broken build on GitLab\") in {}",
+                verb, link
+            ));
+        }
+
+        for action in &["merged", "accepted"] {
+            let merge_request = event(&format!(r#"{{
+                "id": 3, "project_id": 1, "action_name": "{}", "target_type": "MergeRequest",
+                "target_iid": 8, "target_title": "Fix the build",
+                "created_at": "2019-01-01T10:00:00.000Z"
+            }}"#, action));
+
+            assert_eq!(patch_text(&merge_request, &project, WEB_URL).unwrap(), format!(
+                "Merged a merge request in {}:\n\n[Fix the build](https://gitlab.com/ryanmcgrath/social/-/merge_requests/8 \"View this MR on GitLab\")",
+                link
+            ));
+        }
+    }
+
+    #[test]
+    fn links_notes_on_commits_to_the_commit() {
+        let note = event(r#"{
+            "id": 4, "project_id": 1, "action_name": "commented on", "target_type": "Note",
+            "target_title": "Fix the build",
+            "note": { "id": 42, "body": "Nice.", "noteable_type": "Commit", "noteable_iid": null, "commit_id": "a1b2c3d4e5f6" },
+            "created_at": "2019-01-01T10:00:00.000Z"
+        }"#);
+
+        assert_eq!(event_url(&note, &social()), "https://gitlab.com/ryanmcgrath/social/-/commit/a1b2c3d4e5f6#note_42");
+        assert!(patch_text(&note, &social(), WEB_URL).unwrap().ends_with(
+            "on [Fix the build](https://gitlab.com/ryanmcgrath/social/-/commit/a1b2c3d4e5f6 \"View Fix the build on GitLab\")"
+        ));
+    }
+
+    fn project(id: i64, visibility: &str) -> String {
+        format!(
+            r#"{{ "id": {}, "path_with_namespace": "ryanmcgrath/project-{}", "web_url": "https://gitlab.com/ryanmcgrath/project-{}", "visibility": "{}" }}"#,
+            id, id, id, visibility
+        )
+    }
+
+    #[test]
+    fn links_events_and_skips_projects_that_are_not_public() {
+        let events = r#"[
+            {
+                "id": 1, "project_id": 1, "action_name": "commented on", "target_type": "Note",
+                "target_iid": 7, "target_title": "Broken build",
+                "note": { "id": 99, "body": "Fixed by !8", "noteable_type": "MergeRequest", "noteable_iid": 7 },
+                "created_at": "2019-01-01T10:00:00.000Z"
+            },
+            {
+                "id": 2, "project_id": 2, "action_name": "pushed to",
+                "push_data": { "commit_count": 1, "action": "pushed", "ref_type": "branch", "ref": "main", "commit_from": "aaa", "commit_to": "bbb" },
+                "created_at": "2019-01-01T09:00:00.000Z"
+            },
+            {
+                "id": 3, "project_id": 3, "action_name": "pushed to",
+                "push_data": { "commit_count": 2, "action": "pushed", "ref_type": "branch", "ref": "main", "commit_from": "ccc", "commit_to": "ddd" },
+                "created_at": "2019-01-01T08:00:00.000Z"
+            }
+        ]"#;
+
+        let (url, requests) = serve(vec![events.into(), project(1, "public"), project(2, "private"), project(3, "internal")]);
+        let client = Client { url: url.clone(), token: "token".into(), http: reqwest::Client::new(), projects: HashMap::new() };
+        let activities = fetch(client, "ryanmcgrath", &["RyanMcGrath/Project-3".into()]).unwrap();
+
+        assert_eq!(requests.recv().unwrap().header("private-token"), Some("token"));
+        assert_eq!(activities.len(), 2);
+
+        assert_eq!(activities[0].datetime.url, "https://gitlab.com/ryanmcgrath/project-1/-/merge_requests/7#note_99");
+        assert_eq!(activities[0].id, Some(format!("{}/events/1", url)));
+        assert_eq!(
+            activities[0].body.as_deref(),
+            Some(format!("Fixed by [!8]({}/ryanmcgrath/project-1/-/merge_requests/8)", url).as_str())
+        );
+
+        assert_eq!(activities[1].datetime.url, "https://gitlab.com/ryanmcgrath/project-3/-/compare/ccc...ddd");
+    }
+}
@@ -0,0 +1,411 @@
+//! grouping.rs
+//!
+//! Coalesces bursts of related activity into single feed items. Five quick
+//! pushes to the same repo read a lot better as one line than five, and a pull
+//! request that gets opened, pushed to and merged in an afternoon is really
+//! just one thing that happened.
+//!
+//! Grouping relies on the raw payload each `Activity` carries in `source`;
+//! anything without one is passed through untouched.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use chrono::Duration;
+use serde_json::Value;
+
+use crate::{Activity, DateTime, escape};
+
+/// A grouping rule: events of `event` type that land within `window` of each
+/// other get folded together.
+#[derive(Debug)]
+pub struct Rule {
+    pub event: String,
+    pub window: Duration
+}
+
+/// The event types that know how to group.
+const GROUPABLE: [&str; 2] = ["PushEvent", "PullRequestEvent"];
+
+/// Reads rules from `RYMC_GITHUB_GROUPING`, formatted as a comma separated list of
+/// `EventType=minutes` (e.g, `PushEvent=60,PullRequestEvent=1440`). A window of `0`
+/// turns grouping off for that event type.
+pub fn rules_from_env() -> Result<Vec<Rule>, Box<Error>> {
+    let config = var("RYMC_GITHUB_GROUPING").unwrap_or_else(|_| {
+        "PushEvent=60,PullRequestEvent=1440".into()
+    });
+
+    parse_rules(&config)
+}
+
+/// Anything that isn't a groupable event type with a window in minutes is an error,
+/// rather than a rule that silently never applies.
+fn parse_rules(config: &str) -> Result<Vec<Rule>, Box<Error>> {
+    let mut rules: Vec<Rule> = vec![];
+
+    for rule in config.split(",").map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+        let (event, minutes) = match rule.split_once("=") {
+            Some((event, minutes)) => (event.trim(), minutes.trim()),
+            None => return Err(format!("Invalid RYMC_GITHUB_GROUPING rule \"{}\" (expected EventType=minutes)", rule).into())
+        };
+
+        if !GROUPABLE.contains(&event) {
+            return Err(format!(
+                "Unknown event type \"{}\" in RYMC_GITHUB_GROUPING (expected one of {})",
+                event, GROUPABLE.join(", ")
+            ).into());
+        }
+
+        let minutes = match minutes.parse::<i64>() {
+            Ok(minutes) if minutes >= 0 => minutes,
+            _ => return Err(format!("Invalid window \"{}\" for {} in RYMC_GITHUB_GROUPING (expected minutes)", minutes, event).into())
+        };
+
+        if minutes > 0 {
+            rules.push(Rule { event: event.into(), window: Duration::minutes(minutes) });
+        }
+    }
+
+    Ok(rules)
+}
+
+fn window_for(rules: &[Rule], event: &str) -> Option<Duration> {
+    rules.iter().find(|rule| rule.event == event).map(|rule| rule.window)
+}
+
+fn value<'a>(activity: &'a Activity, path: &str) -> Option<&'a Value> {
+    activity.source.as_ref()?.pointer(&format!("/{}", path.replace(".", "/")))
+}
+
+fn string(activity: &Activity, path: &str) -> Option<String> {
+    value(activity, path)?.as_str().map(|s| s.to_string())
+}
+
+fn event_type(activity: &Activity) -> String {
+    string(activity, "type").unwrap_or_default()
+}
+
+fn commits(activity: &Activity) -> i64 {
+    value(activity, "payload.distinct_size").and_then(|v| v.as_i64()).unwrap_or(0)
+}
+
+fn plural(no: i64) -> &'static str {
+    match no {
+        1 => "",
+        _ => "s"
+    }
+}
+
+/// Takes GitHub activity (newest first, as the API returns it) and folds related
+/// events together according to `rules`, linking to `web_url` (e.g, `https://github.com`).
+/// Returns the result newest first.
+pub fn group_activities(activities: Vec<Activity>, rules: &[Rule], web_url: &str) -> Vec<Activity> {
+    // Working oldest-first makes "what happened next" a lot easier to reason about.
+    let mut items: Vec<Option<Activity>> = activities.into_iter().rev().map(Some).collect();
+
+    if let Some(window) = window_for(rules, "PullRequestEvent") {
+        fold_pull_requests(&mut items, window, web_url);
+    }
+
+    let mut items: Vec<Activity> = items.into_iter().flatten().collect();
+    if let Some(window) = window_for(rules, "PushEvent") {
+        items = coalesce_pushes(items, window, web_url);
+    }
+
+    items.reverse();
+    items
+}
+
+/// Folds "opened a PR", any pushes to its branch, and the eventual close/merge
+/// into a single item, provided they all happen within `window` of the opening.
+fn fold_pull_requests(items: &mut [Option<Activity>], window: Duration, web_url: &str) {
+    for i in 0..items.len() {
+        let (repo, pr_url, title, branch, opened_at) = match &items[i] {
+            Some(a) if event_type(a) == "PullRequestEvent" &&
+                string(a, "payload.action").as_deref() == Some("opened") => {
+                match (
+                    string(a, "payload.pull_request.base.repo.full_name"),
+                    string(a, "payload.pull_request.html_url"),
+                    string(a, "payload.pull_request.title"),
+                    string(a, "payload.pull_request.head.ref")
+                ) {
+                    (Some(repo), Some(url), Some(title), Some(branch)) => (repo, url, title, branch, a.datetime.ts),
+                    _ => continue
+                }
+            },
+
+            _ => continue
+        };
+
+        let branch_ref = format!("refs/heads/{}", branch);
+        let mut members: Vec<usize> = vec![];
+        let mut pushed = 0;
+        let mut closing: Option<(usize, bool)> = None;
+
+        for (j, a) in items.iter().enumerate().skip(i + 1) {
+            let a = match a {
+                Some(a) => a,
+                None => continue
+            };
+
+            if a.datetime.ts.signed_duration_since(opened_at) > window {
+                break;
+            }
+
+            match event_type(a).as_ref() {
+                "PushEvent" if string(a, "repo.name").as_ref() == Some(&repo) &&
+                    string(a, "payload.ref").as_ref() == Some(&branch_ref) => {
+                    pushed += commits(a);
+                    members.push(j);
+                },
+
+                "PullRequestEvent" if string(a, "payload.pull_request.html_url").as_ref() == Some(&pr_url) &&
+                    string(a, "payload.action").as_deref() == Some("closed") => {
+                    let merged = value(a, "payload.pull_request.merged").and_then(|v| v.as_bool()).unwrap_or(false);
+                    closing = Some((j, merged));
+                    break;
+                },
+
+                _ => {}
+            }
+        }
+
+        if members.is_empty() && closing.is_none() {
+            continue;
+        }
+
+        let verb = match closing {
+            Some((_, true)) => "Opened and merged",
+            Some((_, false)) => "Opened and closed",
+            None => "Opened"
+        };
+
+        let pushes = match pushed {
+            0 => "".to_string(),
+            no => format!(" (pushing {} more commit{})", no, plural(no))
+        };
+
+        let content = format!(
+            "{} a pull request in [@{}]({}/{} \"View {} on GitHub\"){}:\n\n[{}]({} \"View this PR on GitHub\")",
+            verb, repo, web_url, repo, escape::markdown_link_title(&repo), pushes, escape::markdown_text(&title), pr_url
+        );
+
+        // The folded item lives where the last event in the chain did, but keeps the
+        // opening event as its source so the push pass doesn't pick it up again, and
+        // as its identity so the archive sees one PR growing rather than a new one.
+        // Every event in it gets remembered too, for once the opening drops off.
+        let last = closing.map(|(j, _)| j).or_else(|| members.last().cloned()).unwrap();
+        let ts = items[last].as_ref().unwrap().datetime.ts;
+        let opening = items[i].take().unwrap();
+        let mut ids: Vec<String> = opening.id.iter().cloned().collect();
+
+        for j in members.into_iter().chain(closing.map(|(j, _)| j)) {
+            if let Some(a) = items[j].take() {
+                ids.extend(a.id);
+            }
+        }
+
+        items[last] = Some(Activity {
+            id: opening.id,
+            members: ids,
+            source: opening.source,
+            ..Activity::new("github", content, DateTime {
+                action: "On".into(),
+                url: "".into(),
+                ts: ts
+            })
+        });
+    }
+}
+
+/// Merges consecutive pushes to the same repository, so long as each one lands
+/// within `window` of the one before it.
+fn coalesce_pushes(items: Vec<Activity>, window: Duration, web_url: &str) -> Vec<Activity> {
+    let mut output: Vec<Option<Activity>> = vec![];
+    let mut burst: Vec<Activity> = vec![];
+
+    for item in items {
+        let continues_burst = match burst.last() {
+            Some(last) => event_type(&item) == "PushEvent" &&
+                string(&item, "repo.name") == string(last, "repo.name") &&
+                item.datetime.ts.signed_duration_since(last.datetime.ts) <= window,
+            None => false
+        };
+
+        if !continues_burst {
+            output.push(merge_pushes(std::mem::take(&mut burst), web_url));
+        }
+
+        if event_type(&item) == "PushEvent" {
+            burst.push(item);
+        } else {
+            output.push(Some(item));
+        }
+    }
+
+    output.push(merge_pushes(burst, web_url));
+    output.into_iter().flatten().collect()
+}
+
+fn merge_pushes(mut burst: Vec<Activity>, web_url: &str) -> Option<Activity> {
+    if burst.len() < 2 {
+        return burst.pop();
+    }
+
+    let first = burst.first().unwrap();
+    let last = burst.last().unwrap();
+    let repo = string(last, "repo.name").unwrap_or_default();
+    let no: i64 = burst.iter().map(commits).sum();
+
+    let compare_url = format!(
+        "{}/{}/compare/{}...{}",
+        web_url, repo,
+        string(first, "payload.before").unwrap_or_default(),
+        string(last, "payload.head").unwrap_or_default()
+    );
+
+    let content = format!(
+        "Pushed [{} commit{}]({} \"View these changes on GitHub\") in {} pushes to [@{}]({}/{} \"View {} on GitHub\")",
+        no, plural(no), compare_url, burst.len(), repo, web_url, repo, escape::markdown_link_title(&repo)
+    );
+
+    // The burst is known by its first push, which stays put as later ones join it.
+    let id = burst.first().and_then(|first| first.id.clone());
+    let members: Vec<String> = burst.iter().filter_map(|push| push.id.clone()).collect();
+    let last = burst.pop().unwrap();
+    Some(Activity {
+        id: id,
+        members: members,
+        source: last.source,
+        ..Activity::new("github", content, DateTime {
+            action: last.datetime.action,
+            url: last.datetime.url,
+            ts: last.datetime.ts
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const WEB_URL: &str = "https://github.com";
+
+    fn event(id: &str, ts: &str, source: Value) -> Activity {
+        Activity::new("github", format!("Event {}", id), DateTime {
+            action: "On".into(),
+            url: "".into(),
+            ts: chrono::NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M").unwrap()
+        }).with_id(format!("events/{}", id)).with_source(source)
+    }
+
+    fn push(id: &str, ts: &str, repo: &str, branch: &str, before: &str, head: &str, commits: i64) -> Activity {
+        event(id, ts, json!({
+            "type": "PushEvent",
+            "repo": { "name": repo },
+            "payload": { "ref": format!("refs/heads/{}", branch), "before": before, "head": head, "distinct_size": commits }
+        }))
+    }
+
+    fn pull_request(id: &str, ts: &str, action: &str, merged: bool) -> Activity {
+        event(id, ts, json!({
+            "type": "PullRequestEvent",
+            "repo": { "name": "ryanmcgrath/social" },
+            "payload": {
+                "action": action,
+                "pull_request": {
+                    "html_url": "https://github.com/ryanmcgrath/social/pull/7",
+                    "title": "Group things",
+                    "merged": merged,
+                    "head": { "ref": "grouping" },
+                    "base": { "repo": { "full_name": "ryanmcgrath/social" } }
+                }
+            }
+        }))
+    }
+
+    fn rules() -> Vec<Rule> {
+        parse_rules("PushEvent=60,PullRequestEvent=1440").unwrap()
+    }
+
+    #[test]
+    fn rejects_unknown_event_types() {
+        assert!(parse_rules("PushEvent=60,PushEvnet=30").is_err());
+        assert!(parse_rules("PushEvent").is_err());
+        assert!(parse_rules("PushEvent=soon").is_err());
+    }
+
+    #[test]
+    fn a_zero_window_turns_grouping_off() {
+        let rules = parse_rules("PushEvent=0, PullRequestEvent=30").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].event, "PullRequestEvent");
+        assert_eq!(rules[0].window, Duration::minutes(30));
+    }
+
+    #[test]
+    fn coalesces_pushes_to_the_same_repo() {
+        // Newest first, as the API returns them.
+        let activities = vec![
+            push("4", "2019-01-01 13:00", "ryanmcgrath/social", "master", "c", "d", 1),
+            push("3", "2019-01-01 10:50", "ryanmcgrath/other", "master", "x", "y", 1),
+            push("2", "2019-01-01 10:40", "ryanmcgrath/social", "master", "b", "c", 2),
+            push("1", "2019-01-01 10:00", "ryanmcgrath/social", "master", "a", "b", 1)
+        ];
+
+        let grouped = group_activities(activities, &rules(), WEB_URL);
+        assert_eq!(grouped.len(), 3);
+
+        // 13:00 is more than an hour after 10:40, so it starts over.
+        assert_eq!(grouped[0].content, "Event 4");
+        assert_eq!(grouped[1].content, "Event 3");
+        assert_eq!(
+            grouped[2].content,
+            "Pushed [3 commits](https://github.com/ryanmcgrath/social/compare/a...c \"View these changes on GitHub\") in 2 pushes to [@ryanmcgrath/social](https://github.com/ryanmcgrath/social \"View ryanmcgrath/social on GitHub\")"
+        );
+
+        // Sorted where the last push happened, but known by the first.
+        assert_eq!(grouped[2].datetime.ts, chrono::NaiveDateTime::parse_from_str("2019-01-01 10:40", "%Y-%m-%d %H:%M").unwrap());
+        assert_eq!(grouped[2].id, Some("events/1".into()));
+        assert_eq!(grouped[2].members, vec!["events/1", "events/2"]);
+    }
+
+    #[test]
+    fn folds_a_pull_request_and_its_pushes() {
+        let activities = vec![
+            pull_request("4", "2019-01-01 15:00", "closed", true),
+            push("3", "2019-01-01 12:00", "ryanmcgrath/social", "grouping", "b", "c", 2),
+            push("2", "2019-01-01 11:00", "ryanmcgrath/social", "master", "x", "y", 1),
+            pull_request("1", "2019-01-01 10:00", "opened", false)
+        ];
+
+        let grouped = group_activities(activities, &rules(), WEB_URL);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(
+            grouped[0].content,
+            "Opened and merged a pull request in [@ryanmcgrath/social](https://github.com/ryanmcgrath/social \"View ryanmcgrath/social on GitHub\") (pushing 2 more commits):\n\n[Group things](https://github.com/ryanmcgrath/social/pull/7 \"View this PR on GitHub\")"
+        );
+        assert_eq!(grouped[0].id, Some("events/1".into()));
+        assert_eq!(grouped[0].members, vec!["events/1", "events/3", "events/4"]);
+        assert_eq!(grouped[0].datetime.ts, chrono::NaiveDateTime::parse_from_str("2019-01-01 15:00", "%Y-%m-%d %H:%M").unwrap());
+
+        // A push to some other branch has nothing to do with it.
+        assert_eq!(grouped[1].content, "Event 2");
+    }
+
+    #[test]
+    fn leaves_pull_requests_outside_the_window_alone() {
+        let activities = vec![
+            pull_request("2", "2019-01-03 10:00", "closed", false),
+            pull_request("1", "2019-01-01 10:00", "opened", false)
+        ];
+
+        let grouped = group_activities(activities, &rules(), WEB_URL);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].content, "Event 2");
+        assert_eq!(grouped[1].content, "Event 1");
+    }
+}
@@ -0,0 +1,322 @@
+//! heatmap.rs
+//!
+//! Renders a GitHub-style calendar heatmap of everything in the archive, as a
+//! self-contained SVG the site can drop in as an image - no JavaScript needed.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::collections::{BTreeMap, HashMap};
+use chrono::{Datelike, Duration, NaiveDate};
+
+use crate::archive::ArchivedActivity;
+
+const CELL: i64 = 10;
+const GAP: i64 = 3;
+const TOP: i64 = 15;
+const LEFT: i64 = 28;
+const DEFAULT_PALETTE: &'static str = "#ebedf0,#c6e48b,#7bc96f,#239a3b,#196127";
+
+#[derive(Debug, PartialEq)]
+pub enum WeekStart {
+    Sunday,
+    Monday
+}
+
+/// How the heatmap looks, read from the environment:
+///
+/// - `RYMC_HEATMAP_PALETTE`: comma separated colors, from "nothing" to "a lot". It takes
+///   at least two; anything shorter gets the default (five shades of green) instead.
+/// - `RYMC_HEATMAP_WEEK_START`: `sunday` (the default) or `monday`.
+/// - `RYMC_HEATMAP_TYPE_COLORS`: optional `type=color` pairs (e.g, `twitter=#1da1f2`).
+///   If set, each day is colored after whichever type it had the most of, and the
+///   palette is only used for empty days.
+#[derive(Debug)]
+pub struct HeatmapOptions {
+    pub palette: Vec<String>,
+    pub week_start: WeekStart,
+    pub type_colors: HashMap<String, String>,
+    pub weeks: i64
+}
+
+impl HeatmapOptions {
+    pub fn from_env() -> Self {
+        let palette = match var("RYMC_HEATMAP_PALETTE") {
+            Ok(config) => {
+                let palette = parse_palette(&config);
+                if palette.len() < 2 {
+                    eprintln!("RYMC_HEATMAP_PALETTE needs at least two colors (got \"{}\"); using the default.", config);
+                    parse_palette(DEFAULT_PALETTE)
+                } else {
+                    palette
+                }
+            },
+
+            Err(_) => parse_palette(DEFAULT_PALETTE)
+        };
+
+        let week_start = match var("RYMC_HEATMAP_WEEK_START").unwrap_or_default().as_ref() {
+            "monday" => WeekStart::Monday,
+            _ => WeekStart::Sunday
+        };
+
+        let type_colors = var("RYMC_HEATMAP_TYPE_COLORS").unwrap_or_default().split(",").filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, "=");
+            Some((parts.next()?.trim().to_string(), parts.next()?.trim().to_string()))
+        }).collect();
+
+        HeatmapOptions {
+            palette: palette,
+            week_start: week_start,
+            type_colors: type_colors,
+            weeks: 53
+        }
+    }
+}
+
+fn parse_palette(config: &str) -> Vec<String> {
+    config.split(",").map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+fn plural(no: usize) -> &'static str {
+    match no {
+        1 => "y",
+        _ => "ies"
+    }
+}
+
+/// How far back the heatmap goes, for its title. The default 53 weeks (52 and a bit,
+/// so the oldest column is always complete) reads as a year.
+fn span(weeks: i64) -> String {
+    match weeks {
+        1 => "the last week".into(),
+        52 | 53 => "the last year".into(),
+        _ => format!("the last {} weeks", weeks)
+    }
+}
+
+/// Buckets a day's count into one of the palette's levels, relative to the busiest day.
+fn level(count: usize, max: usize, levels: usize) -> usize {
+    match count {
+        0 => 0,
+        _ => std::cmp::max(1, std::cmp::min(levels - 1, (count * (levels - 1)).div_ceil(max)))
+    }
+}
+
+/// Draws `activities` for the `options.weeks` weeks leading up to (and including) `today`.
+pub fn render(activities: &[ArchivedActivity], options: &HeatmapOptions, today: NaiveDate) -> String {
+    // One color can't tell "nothing" from "something", and none can't draw anything at all.
+    let default_palette = parse_palette(DEFAULT_PALETTE);
+    let palette = match options.palette.len() {
+        0 | 1 => &default_palette,
+        _ => &options.palette
+    };
+
+    let offset = match options.week_start {
+        WeekStart::Sunday => today.weekday().num_days_from_sunday(),
+        WeekStart::Monday => today.weekday().num_days_from_monday()
+    } as i64;
+
+    let start = today - Duration::days(offset + (options.weeks - 1) * 7);
+
+    // Per day, per type counts.
+    let mut days: BTreeMap<NaiveDate, BTreeMap<String, usize>> = BTreeMap::new();
+    for activity in activities {
+        let date = activity.ts.date();
+        if date < start || date > today {
+            continue;
+        }
+
+        *days.entry(date).or_default().entry(activity.activity_type.clone()).or_insert(0) += 1;
+    }
+
+    let totals: BTreeMap<NaiveDate, usize> = days.iter().map(|(date, types)| (*date, types.values().sum())).collect();
+    let max = totals.values().cloned().max().unwrap_or(1);
+    let total: usize = totals.values().sum();
+
+    let width = LEFT + options.weeks * (CELL + GAP);
+    let height = TOP + 7 * (CELL + GAP);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" role=\"img\" aria-labelledby=\"heatmap-title\">\n",
+        width, height, width, height
+    );
+
+    svg.push_str(&format!(
+        "<title id=\"heatmap-title\">{} activit{} in {}</title>\n",
+        total, plural(total), span(options.weeks)
+    ));
+
+    svg.push_str("<g font-family=\"sans-serif\" font-size=\"9\" fill=\"#767676\">\n");
+
+    let weekdays = match options.week_start {
+        WeekStart::Sunday => [(1, "Mon"), (3, "Wed"), (5, "Fri")],
+        WeekStart::Monday => [(0, "Mon"), (2, "Wed"), (4, "Fri")]
+    };
+
+    for (row, label) in weekdays.iter() {
+        svg.push_str(&format!(
+            "<text x=\"0\" y=\"{}\" aria-hidden=\"true\">{}</text>\n",
+            TOP + row * (CELL + GAP) + CELL - 1, label
+        ));
+    }
+
+    let mut month = 0;
+    for week in 0..options.weeks {
+        let first = start + Duration::days(week * 7);
+        if first.month() != month {
+            month = first.month();
+
+            // Don't squash a label into the very first column if the month's nearly over.
+            if week > 0 || first.day() <= 7 {
+                svg.push_str(&format!(
+                    "<text x=\"{}\" y=\"{}\" aria-hidden=\"true\">{}</text>\n",
+                    LEFT + week * (CELL + GAP), TOP - 5, first.format("%b")
+                ));
+            }
+        }
+    }
+
+    svg.push_str("</g>\n");
+
+    let mut day = start;
+    while day <= today {
+        let index = day.signed_duration_since(start).num_days();
+        let count = totals.get(&day).cloned().unwrap_or(0);
+        let types = days.get(&day);
+        let lvl = level(count, max, palette.len());
+
+        let fill = match types.and_then(|types| types.iter().max_by_key(|(_, no)| *no)) {
+            Some((activity_type, _)) if !options.type_colors.is_empty() => {
+                let color = options.type_colors.get(activity_type).or(palette.last()).cloned().unwrap_or_default();
+                format!("fill=\"{}\" fill-opacity=\"{:.2}\"", xml_escape(&color), lvl as f64 / (palette.len() - 1) as f64)
+            },
+
+            _ => format!("fill=\"{}\"", xml_escape(&palette[lvl]))
+        };
+
+        let breakdown: Vec<String> = types.map(|types| types.iter().map(|(activity_type, no)| {
+            format!("{} {}", no, activity_type)
+        }).collect()).unwrap_or_default();
+
+        let title = match count {
+            0 => format!("No activity on {}", day.format("%A, %B %-d %Y")),
+            _ => format!("{} activit{} on {}: {}", count, plural(count), day.format("%A, %B %-d %Y"), breakdown.join(", "))
+        };
+
+        svg.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"2\" {} data-date=\"{}\" data-count=\"{}\"><title>{}</title></rect>\n",
+            LEFT + (index / 7) * (CELL + GAP), TOP + (index % 7) * (CELL + GAP), CELL, CELL,
+            fill, day.format("%Y-%m-%d"), count, xml_escape(&title)
+        ));
+
+        day += Duration::days(1);
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(palette: &[&str]) -> HeatmapOptions {
+        HeatmapOptions {
+            palette: palette.iter().map(|c| c.to_string()).collect(),
+            week_start: WeekStart::Sunday,
+            type_colors: HashMap::new(),
+            weeks: 2
+        }
+    }
+
+    fn activity(ts: &str) -> ArchivedActivity {
+        typed("twitter", ts)
+    }
+
+    fn typed(activity_type: &str, ts: &str) -> ArchivedActivity {
+        serde_json::from_str(&format!(
+            r#"{{"type": "{}", "content": "Hi", "url": "", "action": "Tweeted", "ts": "{}"}}"#, activity_type, ts
+        )).unwrap()
+    }
+
+    fn cell<'a>(svg: &'a str, date: &str) -> &'a str {
+        svg.lines().find(|line| line.contains(&format!("data-date=\"{}\"", date))).unwrap()
+    }
+
+    #[test]
+    fn falls_back_to_the_default_palette() {
+        let today = NaiveDate::from_ymd_opt(2019, 1, 10).unwrap();
+        let activities = vec![activity("2019-01-09T10:00:00Z")];
+
+        for palette in &[vec![], vec!["#000000"]] {
+            let svg = render(&activities, &options(palette), today);
+            assert!(svg.contains("#ebedf0"));
+            assert!(svg.contains("#196127"));
+        }
+    }
+
+    #[test]
+    fn buckets_relative_to_the_busiest_day() {
+        assert_eq!(level(0, 10, 5), 0);
+        assert_eq!(level(1, 10, 5), 1);
+        assert_eq!(level(10, 10, 5), 4);
+        assert_eq!(level(1, 1, 2), 1);
+    }
+
+    #[test]
+    fn places_days_by_week_start() {
+        // A Thursday.
+        let today = NaiveDate::from_ymd_opt(2019, 1, 10).unwrap();
+        let activities = vec![activity("2019-01-09T10:00:00Z")];
+
+        let svg = render(&activities, &options(&["#eeeeee", "#111111"]), today);
+        assert!(svg.contains("<title id=\"heatmap-title\">1 activity in the last 2 weeks</title>"));
+        assert_eq!(
+            cell(&svg, "2018-12-30"),
+            "<rect x=\"28\" y=\"15\" width=\"10\" height=\"10\" rx=\"2\" fill=\"#eeeeee\" data-date=\"2018-12-30\" data-count=\"0\">\
+             <title>No activity on Sunday, December 30 2018</title></rect>"
+        );
+        assert_eq!(
+            cell(&svg, "2019-01-09"),
+            "<rect x=\"41\" y=\"54\" width=\"10\" height=\"10\" rx=\"2\" fill=\"#111111\" data-date=\"2019-01-09\" data-count=\"1\">\
+             <title>1 activity on Wednesday, January 9 2019: 1 twitter</title></rect>"
+        );
+
+        let monday = HeatmapOptions { week_start: WeekStart::Monday, ..options(&["#eeeeee", "#111111"]) };
+        let svg = render(&activities, &monday, today);
+        assert!(!svg.contains("data-date=\"2018-12-30\""));
+        assert!(cell(&svg, "2018-12-31").starts_with("<rect x=\"28\" y=\"15\" "));
+        assert!(cell(&svg, "2019-01-09").starts_with("<rect x=\"41\" y=\"41\" "));
+        assert!(cell(&svg, "2019-01-10").starts_with("<rect x=\"41\" y=\"54\" "));
+    }
+
+    #[test]
+    fn colors_days_by_their_busiest_type() {
+        let today = NaiveDate::from_ymd_opt(2019, 1, 10).unwrap();
+        let activities = vec![
+            activity("2019-01-08T10:00:00Z"),
+            activity("2019-01-08T11:00:00Z"),
+            typed("github", "2019-01-08T12:00:00Z"),
+            typed("github", "2019-01-09T10:00:00Z")
+        ];
+
+        let mut type_colors = HashMap::new();
+        type_colors.insert("twitter".to_string(), "#1da1f2".to_string());
+        let options = HeatmapOptions { type_colors: type_colors, ..options(&["#eeeeee", "#888888", "#111111"]) };
+        let svg = render(&activities, &options, today);
+
+        assert!(cell(&svg, "2019-01-08").contains(
+            "fill=\"#1da1f2\" fill-opacity=\"1.00\" data-date=\"2019-01-08\" data-count=\"3\">\
+             <title>3 activities on Tuesday, January 8 2019: 1 github, 2 twitter</title>"
+        ));
+
+        // No color for the type, so it falls back to the top of the palette.
+        assert!(cell(&svg, "2019-01-09").contains("fill=\"#111111\" fill-opacity=\"0.50\""));
+        assert!(cell(&svg, "2019-01-10").contains("fill=\"#eeeeee\" data-date"));
+    }
+}
@@ -6,12 +6,35 @@
 //! @author Ryan McGrath <ryan@rymc.io>
 //! @copyright RYMC 2019
 
+// `Box<Error>`, `field: field` and `&'static str` are this codebase's style.
+#![allow(bare_trait_objects, clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
+
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate serde_derive;
 
 pub mod twitter;
+pub mod twitter_archive;
+pub mod twitter_v2;
 pub mod github;
+pub mod gists;
+pub mod gitlab;
 pub mod dribbble;
+pub mod mastodon;
+pub mod bluesky;
+pub mod feeds;
+pub mod git;
+pub mod grouping;
+pub mod comments;
+pub mod contributions;
+pub mod archive;
+pub mod heatmap;
+pub mod entities;
+pub mod escape;
+pub mod env;
+pub mod state;
+
+#[cfg(test)]
+mod testing;
 
 use chrono::{NaiveDateTime, Utc};
 use chrono_humanize::{HumanTime, Accuracy, Tense};
@@ -26,12 +49,62 @@ pub struct DateTime {
     pub ts: chrono::NaiveDateTime
 }
 
+/// A piece of media attached to an Activity. `url` is the full-size image (or for video,
+/// the video itself); `preview_url` is a still to show in its place, if there is one.
+#[derive(Serialize, Clone, Debug)]
+pub struct Attachment {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    pub preview_url: Option<String>,
+    pub alt_text: Option<String>
+}
+
+impl Attachment {
+    /// Photos render as images; video (and anything else with a poster) as its poster,
+    /// linking straight to the video file.
+    pub fn to_markdown(&self) -> String {
+        let alt = escape::markdown_text(self.alt_text.as_deref().unwrap_or(""));
+
+        match &self.preview_url {
+            Some(poster) => format!(
+                "[![{}]({})]({} \"Play this {}\")",
+                alt, escape::markdown_url(poster), escape::markdown_url(&self.url), self.kind
+            ),
+
+            None => format!("![{}]({})", alt, escape::markdown_url(&self.url))
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct Activity {
     #[serde(rename = "type")]
     pub activity_type: String,
     pub content: String,
-    pub datetime: DateTime
+    pub datetime: DateTime,
+
+    /// The full text behind `content`, for sources that excerpt long posts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+
+    /// A stable identity (e.g, the ID of the event it came from), for sources where the
+    /// permalink alone doesn't pin an Activity down. The archive keys on this.
+    #[serde(skip)]
+    pub id: Option<String>,
+
+    /// For an item that several events got grouped into, the IDs of all of them. The
+    /// archive uses these to recognize the group once its first event has dropped off.
+    #[serde(skip)]
+    pub members: Vec<String>,
+
+    /// The raw payload this Activity was rendered from, if the source kept it around.
+    /// Used by later passes (e.g, grouping) that need more than the rendered text.
+    #[serde(skip)]
+    pub source: Option<serde_json::Value>
 }
 
 impl Activity {
@@ -39,9 +112,34 @@ impl Activity {
         Activity {
             activity_type: activity_type.to_string(),
             content: content,
-            datetime: datetime
+            datetime: datetime,
+            body: None,
+            attachments: vec![],
+            id: None,
+            members: vec![],
+            source: None
         }
     }
+
+    pub fn with_body(mut self, body: Option<String>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn with_attachments(mut self, attachments: Vec<Attachment>) -> Self {
+        self.attachments = attachments;
+        self
+    }
+
+    pub fn with_id(mut self, id: String) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn with_source(mut self, source: serde_json::Value) -> Self {
+        self.source = Some(source);
+        self
+    }
 }
 
 //const FORMAT: &'static str = "%a %d %B %Y ~%R";
@@ -53,12 +151,32 @@ fn serialize_timestamp<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S:
     serializer.serialize_str(&s.to_text_en(Accuracy::Rough, Tense::Past))
 }
 
-pub fn markdown_link_title_escape(s: &str) -> String {
-    s.replace("\"", "&#34;").replace("(", "&#40;").replace(")", "&#41;")
-}
-
 fn main() {
     dotenv::dotenv().ok();
+
+    if let Some(command) = std::env::args().nth(1) {
+        match command.as_ref() {
+            "backfill-twitter" => match twitter::backfill() {
+                Ok(total) => { println!("Twitter archive now holds {} Tweets.", total); },
+                Err(e) => { eprintln!("Error backfilling Tweets: {:?}", e); std::process::exit(1); }
+            },
+
+            "import-twitter-archive" => {
+                let source = std::env::args().nth(2).expect("Usage: social import-twitter-archive <zip-or-directory>");
+                let path = std::env::var("RYMC_ACTIVITY_PATH").expect("Activity feed filepath not set!");
+
+                match twitter_archive::import(std::path::Path::new(&source), &path) {
+                    Ok(total) => { println!("Imported {} activities from the Twitter archive.", total); },
+                    Err(e) => { eprintln!("Error importing Twitter archive: {:?}", e); std::process::exit(1); }
+                }
+            },
+
+            _ => { eprintln!("Unknown command: {}", command); std::process::exit(1); }
+        }
+
+        return;
+    }
+
     let mut feed: Vec<Activity> = vec![];
 
     match twitter::get_and_transform_tweets_to_html() {
@@ -70,17 +188,67 @@ fn main() {
         Ok(mut activity) => { feed.append(&mut activity); },
         Err(e) => { eprintln!("Error fetching GitHub Activity: {:?}", e); }
     }
+
+    if gitlab::enabled() {
+        match gitlab::get_and_transform_activity_to_html() {
+            Ok(mut activity) => { feed.append(&mut activity); },
+            Err(e) => { eprintln!("Error fetching GitLab Activity: {:?}", e); }
+        }
+    }
     
     match dribbble::get_and_transform_activity_to_html() {
         Ok(mut activity) => { feed.append(&mut activity); },
         Err(e) => { eprintln!("Error fetching Dribbble Shots: {:?}", e); }
     }
 
+    if mastodon::enabled() {
+        match mastodon::get_and_transform_statuses_to_html() {
+            Ok(mut statuses) => { feed.append(&mut statuses); },
+            Err(e) => { eprintln!("Error fetching Mastodon statuses: {:?}", e); }
+        }
+    }
+
+    if bluesky::enabled() {
+        match bluesky::get_and_transform_posts_to_html() {
+            Ok(mut posts) => { feed.append(&mut posts); },
+            Err(e) => { eprintln!("Error fetching Bluesky posts: {:?}", e); }
+        }
+    }
+
+    if feeds::enabled() {
+        match feeds::get_and_transform_feeds_to_html() {
+            Ok(mut items) => { feed.append(&mut items); },
+            Err(e) => { eprintln!("Error fetching feeds: {:?}", e); }
+        }
+    }
+
+    if git::enabled() {
+        match git::get_and_transform_commits_to_html() {
+            Ok(mut commits) => { feed.append(&mut commits); },
+            Err(e) => { eprintln!("Error reading local git commits: {:?}", e); }
+        }
+    }
+
     feed.sort_by(|a, b| {
         b.datetime.ts.cmp(&a.datetime.ts)
     });
     
     let path = std::env::var("RYMC_ACTIVITY_PATH").expect("Activity feed filepath not set!");
-    let contents = serde_json::to_string(&feed[0..12]).expect("Unable to serialize Feed JSON! :(");
-    std::fs::write(&format!("{}/activities.json", path), contents).expect("Could not write activity feed to file!");
+    let contents = serde_json::to_string(&feed[..feed.len().min(12)]).expect("Unable to serialize Feed JSON! :(");
+    std::fs::write(format!("{}/activities.json", path), contents).expect("Could not write activity feed to file!");
+
+    // Everything we've seen goes into the archive, which the heatmap is drawn from.
+    match archive::load(&path) {
+        Ok(mut history) => {
+            archive::merge(&mut history, feed.iter().map(archive::ArchivedActivity::from));
+            if let Err(e) = archive::save(&path, &history) {
+                eprintln!("Error saving activity archive: {:?}", e);
+            }
+
+            let svg = heatmap::render(&history, &heatmap::HeatmapOptions::from_env(), Utc::now().naive_utc().date());
+            std::fs::write(format!("{}/activity-heatmap.svg", path), svg).expect("Could not write activity heatmap to file!");
+        },
+
+        Err(e) => { eprintln!("Error loading activity archive: {:?}", e); }
+    }
 }
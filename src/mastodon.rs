@@ -0,0 +1,383 @@
+//! mastodon.rs
+//!
+//! Fetches statuses (toots) for an account on a Mastodon instance. Mastodon hands
+//! back content as (sanitized) HTML rather than text + entities, so this walks
+//! the handful of tags it actually uses and turns them into markdown.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use regex::Regex;
+use serde::{Deserializer, Deserialize};
+use chrono::NaiveDateTime;
+
+use crate::{Activity, Attachment, DateTime, escape};
+
+lazy_static! {
+    static ref HTML_TAG_REGEX: Regex = Regex::new(r"<(/?)([a-zA-Z0-9]+)([^>]*)>").unwrap();
+    static ref HTML_ATTRIBUTE_REGEX: Regex = Regex::new(r#"([a-zA-Z-]+)="([^"]*)""#).unwrap();
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Account {
+    pub acct: String,
+    pub username: String,
+    pub url: String
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Mention {
+    pub acct: String,
+    pub url: String
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MediaAttachment {
+    #[serde(rename = "type")]
+    pub media_type: String,
+    pub url: String,
+    pub preview_url: Option<String>,
+    pub description: Option<String>
+}
+
+impl MediaAttachment {
+    /// Images render as themselves; anything else (video, GIFs, audio) as its preview.
+    pub fn to_attachment(&self) -> Attachment {
+        let kind = match self.media_type.as_ref() {
+            "gifv" => "gif",
+            kind => kind
+        };
+
+        Attachment {
+            kind: kind.to_string(),
+            url: self.url.clone(),
+            preview_url: match kind {
+                "image" => None,
+                _ => self.preview_url.clone()
+            },
+            alt_text: self.description.clone()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Status {
+    pub id: String,
+    pub uri: String,
+    pub url: Option<String>,
+    pub content: String,
+    pub spoiler_text: String,
+    pub visibility: String,
+
+    #[serde(default)]
+    pub sensitive: bool,
+
+    pub account: Account,
+    pub mentions: Vec<Mention>,
+    pub media_attachments: Vec<MediaAttachment>,
+    pub reblog: Option<Box<Status>>,
+
+    #[serde(deserialize_with = "deserialize_mastodon_timestamp")]
+    pub created_at: NaiveDateTime
+}
+
+impl Status {
+    /// Remote statuses don't always have a `url`; the `uri` always resolves somewhere.
+    pub fn permalink(&self) -> &str {
+        self.url.as_ref().unwrap_or(&self.uri)
+    }
+
+    /// Media marked sensitive, or under a content warning, never shows up front.
+    pub fn hides_media(&self) -> bool {
+        self.sensitive || !self.spoiler_text.trim().is_empty()
+    }
+}
+
+fn deserialize_mastodon_timestamp<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error> where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%M:%S%.fZ").map_err(serde::de::Error::custom)
+}
+
+/// Only runs if there's an instance configured, since most folks won't have one.
+pub fn enabled() -> bool {
+    var("RYMC_MASTODON_INSTANCE").map(|v| !v.is_empty()).unwrap_or(false)
+}
+
+/// A link we're partway through, since its text has to be collected before it can be written.
+struct Link {
+    href: String,
+    class: String,
+    text: String
+}
+
+/// Turns status HTML into markdown. Mastodon only ever sends back paragraphs, line
+/// breaks, links and spans - the spans being how it shortens long URLs (the
+/// `invisible` bits get hidden, and `ellipsis` gets a trailing "…").
+fn html_to_markdown(html: &str, mentions: &[Mention]) -> String {
+    let mut output = String::new();
+    let mut link: Option<Link> = None;
+    let mut spans: Vec<&str> = vec![];
+    let mut cursor = 0;
+
+    for captures in HTML_TAG_REGEX.captures_iter(html) {
+        let tag = captures.get(0).unwrap();
+        let text = escape::decode_html_entities(&html[cursor..tag.start()]);
+        cursor = tag.end();
+
+        if !spans.contains(&"invisible") {
+            match &mut link {
+                Some(link) => link.text.push_str(&text),
+                None => output.push_str(&escape::markdown_text(&text))
+            }
+        }
+
+        let closing = &captures[1] == "/";
+        let attributes: Vec<(String, String)> = HTML_ATTRIBUTE_REGEX.captures_iter(&captures[3]).map(|attribute| {
+            (attribute[1].to_string(), escape::decode_html_entities(&attribute[2]))
+        }).collect();
+
+        let attribute = |name: &str| attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone()).unwrap_or_default();
+
+        match (captures[2].to_lowercase().as_ref(), closing) {
+            ("p", true) => output.push_str("\n\n"),
+            ("br", _) => output.push_str("  \n"),
+
+            ("span", false) => spans.push(match attribute("class").as_ref() {
+                "invisible" => "invisible",
+                "ellipsis" => "ellipsis",
+                _ => ""
+            }),
+
+            ("span", true) => {
+                if let (Some("ellipsis"), Some(link)) = (spans.pop(), &mut link) {
+                    link.text.push('…');
+                }
+            },
+
+            ("a", false) => {
+                link = Some(Link { href: attribute("href"), class: attribute("class"), text: String::new() });
+            },
+
+            ("a", true) => {
+                if let Some(link) = link.take() {
+                    output.push_str(&render_link(&link, mentions));
+                }
+            },
+
+            _ => {}
+        }
+    }
+
+    output.push_str(&escape::markdown_text(&escape::decode_html_entities(&html[cursor..])));
+    output.trim().to_string()
+}
+
+fn render_link(link: &Link, mentions: &[Mention]) -> String {
+    let classes: Vec<&str> = link.class.split_whitespace().collect();
+
+    if classes.contains(&"mention") && link.text.starts_with("@") {
+        // Mentions only show the username; the full `user@instance` is in the mention list.
+        let acct = match mentions.iter().find(|mention| mention.url == link.href) {
+            Some(mention) => mention.acct.clone(),
+            None => link.text[1..].to_string()
+        };
+
+        return format!(
+            "[{}]({} \"View @{} on Mastodon\")",
+            escape::markdown_text(&link.text), escape::markdown_url(&link.href), escape::markdown_link_title(&acct)
+        );
+    }
+
+    if classes.contains(&"hashtag") {
+        return format!(
+            "[{}]({} \"View posts tagged {} on Mastodon\")",
+            escape::markdown_text(&link.text), escape::markdown_url(&link.href), escape::markdown_link_title(&link.text)
+        );
+    }
+
+    format!("[{}]({})", escape::markdown_text(&link.text), escape::markdown_url(&link.href))
+}
+
+/// The status itself, plus any media underneath - unless it's marked sensitive or under
+/// a content warning, in which case it's left out entirely.
+fn patch_text(status: &Status) -> String {
+    let mut text = html_to_markdown(&status.content, &status.mentions);
+    if status.hides_media() {
+        return text;
+    }
+
+    let media: Vec<String> = status.media_attachments.iter().map(|media| {
+        media.to_attachment().to_markdown()
+    }).collect();
+
+    if !media.is_empty() {
+        text = format!("{}\n\n{}", text, media.join(" "));
+    }
+
+    text
+}
+
+/// Boosts credit whoever wrote the status, like retweets do.
+fn render(status: &Status) -> String {
+    match &status.reblog {
+        Some(reblog) => format!(
+            "Boosted [@{}]({} \"View @{} on Mastodon\") {}",
+            escape::markdown_text(&reblog.account.username), escape::markdown_url(&reblog.account.url),
+            escape::markdown_link_title(&reblog.account.acct), patch_text(reblog)
+        ),
+
+        None => patch_text(status)
+    }
+}
+
+/// Calls out to the Mastodon instance and retrieves statuses, then pushes them into a
+/// standard template that'll ultimately be rendered on the HTML side. Statuses behind
+/// a content warning only show the warning; the status itself (without its media) goes
+/// in the `body`.
+pub fn get_and_transform_statuses_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let endpoint = format!(
+        "{}/api/v1/accounts/{}/statuses?limit=20",
+        var("RYMC_MASTODON_INSTANCE")?.trim_end_matches("/"), var("RYMC_MASTODON_ACCOUNT_ID")?
+    );
+
+    let client = reqwest::Client::new();
+    let mut request = client.get(&endpoint);
+    if let Ok(token) = var("RYMC_MASTODON_ACCESS_TOKEN") {
+        request = request.bearer_auth(token);
+    }
+
+    let statuses: Vec<Status> = request.send()?.json()?;
+    Ok(statuses_to_activities(statuses))
+}
+
+fn statuses_to_activities(statuses: Vec<Status>) -> Vec<Activity> {
+    let mut activities: Vec<Activity> = vec![];
+    for status in statuses {
+        // With a token, we can see followers-only and direct statuses too. Those stay private.
+        if status.visibility != "public" && status.visibility != "unlisted" {
+            continue;
+        }
+
+        let original = status.reblog.as_ref().map(|reblog| reblog.as_ref()).unwrap_or(&status);
+        let text = render(&status);
+        let attachments: Vec<Attachment> = match original.hides_media() {
+            true => vec![],
+            false => original.media_attachments.iter().map(|media| media.to_attachment()).collect()
+        };
+
+        let activity = match original.spoiler_text.trim() {
+            "" => Activity::new("mastodon", text, DateTime {
+                action: "Posted".into(),
+                url: status.permalink().to_string(),
+                ts: status.created_at
+            }),
+
+            warning => Activity::new("mastodon", format!("Content warning: {}", escape::markdown_text(warning)), DateTime {
+                action: "Posted".into(),
+                url: status.permalink().to_string(),
+                ts: status.created_at
+            }).with_body(Some(text))
+        };
+
+        activities.push(activity.with_attachments(attachments));
+    }
+
+    activities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn status_json(json: Value) -> Value {
+        let mut status = json!({
+            "id": "1",
+            "uri": "https://mastodon.social/users/ryanmcgrath/statuses/1",
+            "url": "https://mastodon.social/@ryanmcgrath/1",
+            "content": "<p>Look</p>",
+            "spoiler_text": "",
+            "visibility": "public",
+            "account": { "acct": "ryanmcgrath", "username": "ryanmcgrath", "url": "https://mastodon.social/@ryanmcgrath" },
+            "mentions": [],
+            "media_attachments": [{
+                "type": "image",
+                "url": "https://files.mastodon.social/a.png",
+                "preview_url": "https://files.mastodon.social/a-small.png",
+                "description": "A dog"
+            }],
+            "reblog": null,
+            "created_at": "2019-01-01T10:00:00.000Z"
+        });
+
+        for (key, value) in json.as_object().unwrap() {
+            status[key] = value.clone();
+        }
+
+        status
+    }
+
+    fn status(json: Value) -> Status {
+        serde_json::from_value(status_json(json)).unwrap()
+    }
+
+    #[test]
+    fn shows_media_on_ordinary_statuses() {
+        let activities = statuses_to_activities(vec![status(json!({}))]);
+        assert_eq!(activities[0].content, "Look\n\n![A dog](https://files.mastodon.social/a.png)");
+        assert_eq!(activities[0].attachments.len(), 1);
+    }
+
+    #[test]
+    fn leaves_sensitive_media_out() {
+        let activities = statuses_to_activities(vec![status(json!({ "sensitive": true }))]);
+        assert_eq!(activities[0].content, "Look");
+        assert!(activities[0].attachments.is_empty());
+    }
+
+    #[test]
+    fn leaves_media_under_content_warnings_out() {
+        // Not flagged sensitive, but the warning alone is enough to keep the media out.
+        let activities = statuses_to_activities(vec![status(json!({ "spoiler_text": "Dogs", "sensitive": false }))]);
+        assert_eq!(activities[0].content, "Content warning: Dogs");
+        assert_eq!(activities[0].body.as_deref(), Some("Look"));
+        assert!(activities[0].attachments.is_empty());
+    }
+
+    #[test]
+    fn checks_the_boosted_status_for_warnings() {
+        let boost = status(json!({
+            "content": "",
+            "media_attachments": [],
+            "reblog": status_json(json!({ "spoiler_text": "Dogs" }))
+        }));
+
+        let activities = statuses_to_activities(vec![boost]);
+        assert!(activities[0].attachments.is_empty());
+    }
+
+    #[test]
+    fn converts_mastodon_html() {
+        let html = concat!(
+            r#"<p><span class="h-card"><a href="https://hachyderm.io/@alice" class="u-url mention">@<span>alice</span></a></span> "#,
+            r#"have you seen <a href="https://mastodon.social/tags/rust" class="mention hashtag" rel="tag">#<span>Rust</span></a>?</p>"#,
+            r#"<p>Tom &amp; Jerry &lt;3<br />It&#39;s at <a href="https://github.com/ryanmcgrath/social/blob/master/src/mastodon.rs" "#,
+            r#"target="_blank" rel="nofollow noopener noreferrer"><span class="invisible">https://</span>"#,
+            r#"<span class="ellipsis">github.com/ryanmcgrath/social/</span><span class="invisible">blob/master/src/mastodon.rs</span></a></p>"#
+        );
+
+        let mentions = vec![Mention { acct: "alice@hachyderm.io".into(), url: "https://hachyderm.io/@alice".into() }];
+
+        assert_eq!(html_to_markdown(html, &mentions), concat!(
+            "[@alice](https://hachyderm.io/@alice \"View @alice@hachyderm.io on Mastodon\") ",
+            "have you seen [\\#Rust](https://mastodon.social/tags/rust \"View posts tagged #Rust on Mastodon\")?\n\n",
+            "Tom & Jerry \\<3  \n",
+            "It's at [github.com/ryanmcgrath/social/…](https://github.com/ryanmcgrath/social/blob/master/src/mastodon.rs)"
+        ));
+
+        // Without the mention list, all we've got is the username.
+        assert!(html_to_markdown(html, &[]).starts_with("[@alice](https://hachyderm.io/@alice \"View @alice on Mastodon\")"));
+    }
+}
@@ -0,0 +1,20 @@
+//! state.rs
+//!
+//! Reading back the JSON files we keep between runs (archives, and what each source
+//! remembers about where it got to).
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::error::Error;
+use serde::de::DeserializeOwned;
+
+/// Reads `file` as JSON. A missing file is just the first run, so that's the default
+/// (empty) value rather than an error.
+pub fn load_json<T>(file: &str) -> Result<T, Box<Error>> where T: DeserializeOwned + Default {
+    match std::fs::read_to_string(file) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(Box::new(e))
+    }
+}
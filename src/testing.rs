@@ -0,0 +1,126 @@
+//! testing.rs
+//!
+//! Helpers for tests: a tiny local HTTP server that plays back recorded responses,
+//! so sources can be tested end to end without going anywhere near the network.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::github::Account;
+
+/// A request the server received: the request line (e.g, `GET /path HTTP/1.1`), headers
+/// (names lowercased) and body.
+#[derive(Debug)]
+pub struct Request {
+    pub line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response to play back: a status code, and a (JSON) body. A plain `String` is a
+/// `200 OK`.
+#[derive(Debug)]
+pub struct Reply {
+    pub status: u16,
+    pub body: String
+}
+
+impl From<String> for Reply {
+    fn from(body: String) -> Self {
+        Reply { status: 200, body: body }
+    }
+}
+
+/// A reply with some status other than `200 OK`, e.g, `status(429, "...")`.
+pub fn status(status: u16, body: &str) -> Reply {
+    Reply { status: status, body: body.into() }
+}
+
+/// Serves `responses` (as JSON) in order, one per request, then stops. Returns the base
+/// URL to point a source at, and a channel of the requests it got.
+pub fn serve(responses: Vec<String>) -> (String, Receiver<Request>) {
+    serve_with(|_| responses)
+}
+
+/// Like `serve`, for responses that need to point back at the server itself (e.g, a
+/// link to fetch next): `responses` gets handed the base URL to build them with. They
+/// can be `Reply`s, too, to answer with some other status.
+pub fn serve_with<F, R>(responses: F) -> (String, Receiver<Request>) where F: FnOnce(&str) -> Vec<R>, R: Into<Reply> {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind a local port for the test server");
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let responses: Vec<Reply> = responses(&url).into_iter().map(Into::into).collect();
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        for reply in responses {
+            let (mut stream, _) = match listener.accept() {
+                Ok(connection) => connection,
+                Err(_) => return
+            };
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            let mut headers: Vec<(String, String)> = vec![];
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+
+                if let Some((name, value)) = header.split_once(':') {
+                    headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+                }
+            }
+
+            let length: usize = headers.iter().find(|(name, _)| name == "content-length")
+                .and_then(|(_, value)| value.parse().ok()).unwrap_or(0);
+
+            let mut request_body = vec![0; length];
+            reader.read_exact(&mut request_body).unwrap();
+
+            // Recorded before answering, so it's there by the time the caller gets a response.
+            sender.send(Request {
+                line: line.trim().to_string(),
+                headers: headers,
+                body: String::from_utf8_lossy(&request_body).into_owned()
+            }).ok();
+
+            write!(
+                stream,
+                "HTTP/1.1 {} Reply\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.status, reply.body.len(), reply.body
+            ).unwrap();
+        }
+    });
+
+    (url, receiver)
+}
+
+/// The path each request asked for, in order (e.g, `/users/ryanmcgrath/repos?page=1`).
+pub fn paths(requests: &Receiver<Request>) -> Vec<String> {
+    requests.try_iter().map(|request| request.line.split(' ').nth(1).unwrap_or_default().to_string()).collect()
+}
+
+/// A github.com account whose API lives at `url` (e.g, one handed back by `serve`).
+pub fn account(url: &str) -> Account {
+    Account {
+        username: "ryanmcgrath".into(),
+        access_token: "token".into(),
+        api_url: url.into(),
+        web_url: "https://github.com".into()
+    }
+}
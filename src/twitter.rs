@@ -7,24 +7,38 @@
 
 use std::env::var;
 use std::error::Error;
+use std::collections::{HashMap, HashSet};
 
 use serde::{Deserialize, Deserializer};
+use serde_json::Value;
 use chrono::NaiveDateTime;
 use oauth_client::{get, Token, ParamList};
 
-use crate::{Activity, DateTime, markdown_link_title_escape};
+use crate::{Activity, Attachment, DateTime, escape};
+use crate::entities::{Span, splice};
+use crate::state::load_json;
+use crate::twitter_v2;
+
+// The timeline only goes back 3,200 Tweets, at most 200 a page (100, on v2).
+const PAGE_SIZE: usize = 200;
+const TIMELINE_LIMIT: usize = 3200;
+
+// How many of the newest archived Tweets get rendered for the feed.
+const FEED_TWEETS: usize = 50;
 
 #[derive(Deserialize, Debug)]
 pub struct Url {
     pub url: String,
     pub display_url: String,
     pub expanded_url: String,
+    #[serde(deserialize_with = "parse_indices")]
     pub indices: [u32; 2]
 }
 
 #[derive(Deserialize, Debug)]
 pub struct HashTag {
     pub text: String,
+    #[serde(deserialize_with = "parse_indices")]
     pub indices: [u32; 2]
 }
 
@@ -32,15 +46,70 @@ pub struct HashTag {
 pub struct UserMention {
     pub screen_name: String,
     pub id_str: String,
+    #[serde(deserialize_with = "parse_indices")]
     pub indices: [u32; 2]
 }
 
+#[derive(Deserialize, Debug)]
+pub struct VideoVariant {
+    #[serde(default, deserialize_with = "parse_optional_number")]
+    pub bitrate: Option<u64>,
+    pub content_type: String,
+    pub url: String
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VideoInfo {
+    pub variants: Vec<VideoVariant>
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Media {
     pub id_str: String,
     pub url: String,
     pub display_url: String,
-    pub expanded_url: String
+    pub expanded_url: String,
+    #[serde(deserialize_with = "parse_indices")]
+    pub indices: [u32; 2],
+
+    #[serde(rename = "type")]
+    pub media_type: Option<String>,
+    pub media_url_https: Option<String>,
+    pub ext_alt_text: Option<String>,
+    pub video_info: Option<VideoInfo>
+}
+
+impl Media {
+    /// Native photos, videos and GIFs as an `Attachment`. Video becomes its best-bitrate
+    /// MP4, with the thumbnail Twitter generates as the poster.
+    pub fn to_attachment(&self) -> Option<Attachment> {
+        let image = self.media_url_https.clone()?;
+
+        match self.media_type.as_deref() {
+            Some("video") | Some("animated_gif") => {
+                let variant = self.video_info.as_ref()?.variants.iter().filter(|variant| {
+                    variant.content_type == "video/mp4"
+                }).max_by_key(|variant| variant.bitrate.unwrap_or(0))?;
+
+                Some(Attachment {
+                    kind: match self.media_type.as_deref() {
+                        Some("animated_gif") => "gif".into(),
+                        _ => "video".into()
+                    },
+                    url: variant.url.clone(),
+                    preview_url: Some(image),
+                    alt_text: self.ext_alt_text.clone()
+                })
+            },
+
+            _ => Some(Attachment {
+                kind: "image".into(),
+                url: image,
+                preview_url: None,
+                alt_text: self.ext_alt_text.clone()
+            })
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -65,102 +134,722 @@ pub struct User {
 pub struct Tweet {
     pub id_str: String,
     pub full_text: String,
+    #[serde(default, deserialize_with = "parse_optional_indices")]
+    pub display_text_range: Option<[u32; 2]>,
     pub lang: String,
     pub user: User,
     pub entities: Entities,
     pub extended_entities: Option<ExtendedEntities>,
     pub retweeted_status: Option<serde_json::Value>,
+    pub quoted_status: Option<serde_json::Value>,
+    pub in_reply_to_status_id_str: Option<String>,
+    pub in_reply_to_screen_name: Option<String>,
 
     #[serde(deserialize_with = "parse_twitter_dt")]
     pub created_at: NaiveDateTime
 }
 
+impl Tweet {
+    /// Whether this is a reply to one of the author's own Tweets, i.e, part of a thread.
+    pub fn is_self_reply(&self) -> bool {
+        match &self.in_reply_to_screen_name {
+            Some(screen_name) => screen_name.eq_ignore_ascii_case(&self.user.screen_name),
+            None => false
+        }
+    }
+}
+
+/// Which replies make it into the feed, via `RYMC_TWITTER_REPLIES`: `all` (the default),
+/// `self` for only replies to your own Tweets (threads), or `none`.
+#[derive(Debug, PartialEq)]
+pub enum ReplyPolicy {
+    All,
+    SelfThreads,
+    Exclude
+}
+
+impl ReplyPolicy {
+    pub fn from_env() -> Self {
+        match var("RYMC_TWITTER_REPLIES").unwrap_or_default().as_ref() {
+            "self" => ReplyPolicy::SelfThreads,
+            "none" => ReplyPolicy::Exclude,
+            _ => ReplyPolicy::All
+        }
+    }
+
+    pub fn allows(&self, tweet: &Tweet) -> bool {
+        match (self, &tweet.in_reply_to_status_id_str) {
+            (_, None) | (ReplyPolicy::All, _) => true,
+            (ReplyPolicy::SelfThreads, Some(_)) => tweet.is_self_reply(),
+            (ReplyPolicy::Exclude, Some(_)) => false
+        }
+    }
+}
+
+/// The downloadable archive writes every number as a string (`"indices": ["0", "12"]`),
+/// where the API doesn't - so anything numeric that both of them feed us takes either.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Number(u64),
+    Text(String)
+}
+
+impl Number {
+    fn value<E>(self) -> Result<u64, E> where E: serde::de::Error {
+        match self {
+            Number::Number(no) => Ok(no),
+            Number::Text(s) => s.parse().map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+fn parse_indices<'de, D>(deserializer: D) -> Result<[u32; 2], D::Error> where D: Deserializer<'de> {
+    let [start, end]: [Number; 2] = Deserialize::deserialize(deserializer)?;
+    Ok([start.value()? as u32, end.value()? as u32])
+}
+
+fn parse_optional_indices<'de, D>(deserializer: D) -> Result<Option<[u32; 2]>, D::Error> where D: Deserializer<'de> {
+    match Option::<[Number; 2]>::deserialize(deserializer)? {
+        Some([start, end]) => Ok(Some([start.value()? as u32, end.value()? as u32])),
+        None => Ok(None)
+    }
+}
+
+fn parse_optional_number<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error> where D: Deserializer<'de> {
+    match Option::<Number>::deserialize(deserializer)? {
+        Some(no) => Ok(Some(no.value()?)),
+        None => Ok(None)
+    }
+}
+
 fn parse_twitter_dt<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error> where D: Deserializer<'de> {
     let s = String::deserialize(deserializer)?;
     NaiveDateTime::parse_from_str(&s, "%a %b %d %H:%M:%S %z %Y").map_err(serde::de::Error::custom)
 }
 
-fn patch_text(mut text: String, tweet: &Tweet) -> String {
+fn patch_text(tweet: &Tweet) -> String {
     // RTs get some entities of their own, so we'll recurse slightly to cover them.
     if let Some(retweeted_status) = &tweet.retweeted_status {
         if let Ok(retweet) = serde_json::from_value::<Tweet>(retweeted_status.clone()) {
             return format!(
                 "RT [@{}](https://twitter.com/{} \"View {} on Twitter\") {}",
-                retweet.user.screen_name, retweet.user.screen_name,
-                markdown_link_title_escape(&retweet.user.screen_name),
-                patch_text(retweet.full_text.clone(), &retweet)
+                escape::markdown_text(&retweet.user.screen_name), retweet.user.screen_name,
+                escape::markdown_link_title(&retweet.user.screen_name),
+                patch_text(&retweet)
             );
         }
     }
 
+    let mut spans: Vec<Span> = vec![];
+
     for mention in tweet.entities.user_mentions.iter() {
-        let x = format!("@{}", mention.screen_name);
-        text = text.replace(&x, &format!(
+        spans.push(Span::new(mention.indices, format!(
             "[@{}](https://twitter.com/{} \"View @{} on Twitter\")",
-            mention.screen_name, mention.screen_name,
-            markdown_link_title_escape(&mention.screen_name)
-        ));
+            escape::markdown_text(&mention.screen_name), mention.screen_name,
+            escape::markdown_link_title(&mention.screen_name)
+        )));
     }
 
     for hashtag in tweet.entities.hashtags.iter() {
-        let x = format!("#{}", hashtag.text);
-        text = text.replace(&x, &format!(
+        spans.push(Span::new(hashtag.indices, format!(
             "[#{}](https://twitter.com/hashtag/{} \"View #{} on Twitter\")",
-            hashtag.text, hashtag.text, markdown_link_title_escape(&hashtag.text)
-        ));
+            escape::markdown_text(&hashtag.text), escape::markdown_url(&hashtag.text),
+            escape::markdown_link_title(&hashtag.text)
+        )));
     }
 
     for url in tweet.entities.urls.iter() {
-        text = text.replace(&url.url, &format!(
+        spans.push(Span::new(url.indices, format!(
             "[{}]({})",
-            url.display_url, url.expanded_url
-        ));
+            escape::markdown_text(&url.display_url), escape::markdown_url(&url.expanded_url)
+        )));
     }
 
+    // Media links get dropped from the text; the media itself gets rendered underneath.
     if let Some(media_entities) = &tweet.entities.media {
         for media in media_entities.iter() {
-            text = text.replace(&media.url, "");
+            spans.push(Span::new(media.indices, "".into()));
         }
     }
 
-    // Native media only exists if it's actually native. It's weird, but we'll just replace it with
-    // the nicer URL for now... maybe down the road we'll auto-load images or something.
-    if let Some(entities) = &tweet.extended_entities {
-        for media in entities.media.iter() {
-            text = text.replace(&media.url, &format!(
-                "[https://{}](https://{})",
-                media.display_url, media.display_url
-            ));
+    // Twitter hands back `&amp;`, `&lt;` and `&gt;` escaped, but the indices all count them as
+    // a single character - so decode first, then splice, escaping the text between entities
+    // for markdown as we go.
+    let text = escape::decode_html_entities(&tweet.full_text);
+    let mut text = splice(&text, spans, tweet.display_text_range, escape::markdown_text);
+
+    let media: Vec<String> = native_media(tweet).iter().map(|media| media.to_markdown()).collect();
+    if !media.is_empty() {
+        text = format!("{}\n\n{}", text, media.join(" "));
+    }
+
+    // Quote Tweets get the quoted Tweet embedded underneath, as a blockquote.
+    if let Some(quoted_status) = &tweet.quoted_status {
+        if let Ok(quoted) = serde_json::from_value::<Tweet>(quoted_status.clone()) {
+            let quote = format!(
+                "[@{}](https://twitter.com/{}/status/{} \"View this Tweet on Twitter\"): {}",
+                escape::markdown_text(&quoted.user.screen_name), quoted.user.screen_name,
+                quoted.id_str, patch_text(&quoted)
+            );
+
+            let lines: Vec<String> = quote.lines().map(|line| format!("> {}", line)).collect();
+            text = format!("{}\n\n{}", text, lines.join("\n"));
         }
     }
 
     text
 }
 
-/// Calls out to Twitter and retrieves Tweets, then pushes them into a standard
-/// template that'll ultimately be rendered on the HTML side.
-pub fn get_and_transform_tweets_to_html() -> Result<Vec<Activity>, Box<Error>> {
+/// Native media only exists if it's actually native - `extended_entities` has all of it
+/// (`entities` only ever has the first photo).
+fn native_media(tweet: &Tweet) -> Vec<Attachment> {
+    match &tweet.extended_entities {
+        Some(entities) => entities.media.iter().filter_map(|media| media.to_attachment()).collect(),
+        None => vec![]
+    }
+}
+
+/// Every piece of media in a Tweet, including whatever's in a retweet or quote.
+fn attachments(tweet: &Tweet) -> Vec<Attachment> {
+    let mut attachments = native_media(tweet);
+
+    for status in tweet.retweeted_status.iter().chain(tweet.quoted_status.iter()) {
+        if let Ok(nested) = serde_json::from_value::<Tweet>(status.clone()) {
+            attachments.append(&mut native_media(&nested));
+        }
+    }
+
+    attachments
+}
+
+/// Replies lose their leading @mentions to `display_text_range`, so this says who it's to.
+fn reply_context(tweet: &Tweet) -> Option<String> {
+    match (&tweet.in_reply_to_status_id_str, &tweet.in_reply_to_screen_name) {
+        (Some(id), Some(screen_name)) => Some(format!(
+            "Replying to [@{}](https://twitter.com/{}/status/{} \"View the Tweet this replies to on Twitter\")",
+            escape::markdown_text(screen_name), screen_name, id
+        )),
+
+        _ => None
+    }
+}
+
+/// Sorts Tweets into threads: a reply to your own Tweet gets tacked onto that Tweet's
+/// thread, provided we've got it. Everything else starts a thread of its own. Each
+/// thread comes back oldest Tweet first.
+fn collect_threads(mut tweets: Vec<Tweet>) -> Vec<Vec<Tweet>> {
+    tweets.sort_by_key(|tweet| tweet.created_at);
+
+    let mut threads: Vec<Vec<Tweet>> = vec![];
+    let mut thread_for: HashMap<String, usize> = HashMap::new();
+
+    for tweet in tweets {
+        let parent = match &tweet.in_reply_to_status_id_str {
+            Some(id) if tweet.is_self_reply() => thread_for.get(id).cloned(),
+            _ => None
+        };
+
+        let index = parent.unwrap_or(threads.len());
+        thread_for.insert(tweet.id_str.clone(), index);
+
+        match parent {
+            Some(index) => threads[index].push(tweet),
+            None => threads.push(vec![tweet])
+        }
+    }
+
+    threads
+}
+
+/// Renders a thread (which is usually just the one Tweet) as a single piece of content.
+/// Only the first Tweet gets reply context, since the rest are replies to the thread.
+fn render_thread(thread: &[Tweet]) -> String {
+    let mut parts: Vec<String> = thread.iter().map(patch_text).collect();
+
+    if let Some(context) = reply_context(&thread[0]) {
+        parts.insert(0, context);
+    }
+
+    parts.join("\n\n")
+}
+
+/// Turns Tweets (from wherever) into activities, minus any replies `RYMC_TWITTER_REPLIES`
+/// leaves out. Threads collapse into one activity, which is the first Tweet's - its ID,
+/// link and time - so a thread that grows later is still the same activity.
+pub fn tweets_to_activities(tweets: Vec<Tweet>) -> Vec<Activity> {
+    tweets_to_activities_with(tweets, &ReplyPolicy::from_env())
+}
+
+fn tweets_to_activities_with(tweets: Vec<Tweet>, replies: &ReplyPolicy) -> Vec<Activity> {
+    let tweets: Vec<Tweet> = tweets.into_iter().filter(|tweet| replies.allows(tweet)).collect();
+    threads_to_activities(collect_threads(tweets))
+}
+
+/// Like `tweets_to_activities`, but only for the threads the newest `count` Tweets are
+/// in. Threads get pieced together from all of `tweets` first, so one that started
+/// further back than that still starts where it really did.
+fn recent_tweets_to_activities(tweets: Vec<Tweet>, count: usize, replies: &ReplyPolicy) -> Vec<Activity> {
+    let mut ids: Vec<u64> = tweets.iter().map(|tweet| tweet.id_str.parse().unwrap_or(0)).collect();
+    ids.sort_by_key(|id| std::cmp::Reverse(*id));
+    let recent: HashSet<u64> = ids.into_iter().take(count).collect();
+
+    let tweets: Vec<Tweet> = tweets.into_iter().filter(|tweet| replies.allows(tweet)).collect();
+    threads_to_activities(collect_threads(tweets).into_iter().filter(|thread| {
+        thread.iter().any(|tweet| recent.contains(&tweet.id_str.parse().unwrap_or(0)))
+    }).collect())
+}
+
+fn threads_to_activities(threads: Vec<Vec<Tweet>>) -> Vec<Activity> {
+    threads.into_iter().map(|thread| {
+        let root = &thread[0];
+
+        Activity::new("twitter", render_thread(&thread), DateTime {
+            action: "Tweeted".into(),
+            url: format!("https://twitter.com/ryanmcgrath/status/{}", root.id_str),
+            ts: root.created_at
+        }).with_id(root.id_str.clone()).with_attachments(thread.iter().flat_map(attachments).collect())
+    }).collect()
+}
+
+/// What we remember between runs: the newest Tweet we've seen, so the next run only
+/// has to ask for what's come in since.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct TimelineState {
+    pub newest_id: Option<String>
+}
+
+fn state_path(path: &str) -> String {
+    format!("{}/twitter-state.json", path)
+}
+
+fn archive_path(path: &str) -> String {
+    format!("{}/twitter-archive.json", path)
+}
+
+fn tweet_id(tweet: &Value) -> u64 {
+    tweet["id_str"].as_str().and_then(|id| id.parse().ok()).unwrap_or(0)
+}
+
+/// Folds `tweets` into the raw archive, newest first. Tweets are kept exactly as Twitter
+/// sent them, so the feed can always be re-rendered from scratch.
+fn merge_tweets(archive: &mut Vec<Value>, tweets: Vec<Value>) {
+    let mut seen: HashSet<u64> = archive.iter().map(tweet_id).collect();
+
+    for tweet in tweets {
+        if seen.insert(tweet_id(&tweet)) {
+            archive.push(tweet);
+        }
+    }
+
+    archive.sort_by_key(|tweet| std::cmp::Reverse(tweet_id(tweet)));
+}
+
+/// Which API the timeline comes from, via `RYMC_TWITTER_BACKEND`: `v1` (the default;
+/// v1.1 with OAuth 1.0a) or `v2` (app-only bearer auth). Both hand back v1.1-shaped Tweets.
+#[derive(Debug, PartialEq)]
+pub enum Backend {
+    V1,
+    V2
+}
+
+impl Backend {
+    pub fn from_env() -> Self {
+        match var("RYMC_TWITTER_BACKEND").unwrap_or_default().as_ref() {
+            "v2" => Backend::V2,
+            _ => Backend::V1
+        }
+    }
+
+    fn max_pages(&self) -> usize {
+        match self {
+            Backend::V1 => TIMELINE_LIMIT / PAGE_SIZE,
+            Backend::V2 => TIMELINE_LIMIT / twitter_v2::PAGE_SIZE
+        }
+    }
+
+    fn fetch_page(&self, since_id: Option<&str>, max_id: Option<u64>) -> Result<Vec<Value>, Box<Error>> {
+        match self {
+            Backend::V1 => fetch_page(since_id, max_id),
+            Backend::V2 => twitter_v2::fetch_page(since_id, max_id)
+        }
+    }
+}
+
+/// One page of the timeline. `since_id` is exclusive, `max_id` inclusive.
+fn fetch_page(since_id: Option<&str>, max_id: Option<u64>) -> Result<Vec<Value>, Box<Error>> {
     let endpoint = "https://api.twitter.com/1.1/statuses/user_timeline.json";
     let consumer = Token::new(var("RYMC_TWITTER_CONSUMER_KEY")?, var("RYMC_TWITTER_CONSUMER_SECRET")?);
     let access = Token::new(var("RYMC_TWITTER_OAUTH_TOKEN")?, var("RYMC_TWITTER_OAUTH_SECRET")?);
 
     let mut options = ParamList::new();
     options.insert("tweet_mode".into(), "extended".into());
-    options.insert("count".into(), "10".into());
+    options.insert("include_ext_alt_text".into(), "true".into());
+    options.insert("include_rts".into(), "true".into());
+    options.insert("count".into(), PAGE_SIZE.to_string().into());
     options.insert("screen_name".into(), "ryanmcgrath".into());
 
+    if let Some(since_id) = since_id {
+        options.insert("since_id".into(), since_id.to_string().into());
+    }
+
+    if let Some(max_id) = max_id {
+        options.insert("max_id".into(), max_id.to_string().into());
+    }
+
     let bytes = get(endpoint, &consumer, Some(&access), Some(&options))?;
     let response = String::from_utf8(bytes)?;
-    let mut tweets: Vec<Tweet> = serde_json::from_str(&response)?;
+    Ok(serde_json::from_str(&response)?)
+}
 
-    let mut activities: Vec<Activity> = vec![];
-    for tweet in tweets.iter_mut() {
-        activities.push(Activity::new("twitter", patch_text(tweet.full_text.clone(), &tweet), DateTime {
-            action: "Tweeted".into(),
-            url: format!("https://twitter.com/ryanmcgrath/status/{}", tweet.id_str),
-            ts: tweet.created_at
+/// Walks back through the timeline a page at a time, stopping at `since_id` (if given),
+/// an empty page, or after `pages` pages. `fetch_page` gets `since_id` and `max_id` and
+/// hands back one page (see `Backend::fetch_page`).
+fn fetch_timeline<F>(mut fetch_page: F, since_id: Option<&str>, pages: usize) -> Result<Vec<Value>, Box<Error>>
+    where F: FnMut(Option<&str>, Option<u64>) -> Result<Vec<Value>, Box<Error>>
+{
+    let mut tweets: Vec<Value> = vec![];
+    let mut max_id: Option<u64> = None;
+
+    for _ in 0..pages {
+        let page = fetch_page(since_id, max_id)?;
+        let oldest = match page.iter().map(tweet_id).min() {
+            Some(id) if id > 0 => id,
+            _ => break
+        };
+
+        tweets.extend(page);
+        max_id = Some(oldest - 1);
+    }
+
+    Ok(tweets)
+}
+
+/// Pulls new Tweets into the archive (and updates the state to match), returning the
+/// archive. Normally that's everything since the newest Tweet we know of (or just the
+/// latest page, the first time around); `backfill` goes back as far as Twitter allows.
+fn sync(path: &str, backfill: bool) -> Result<Vec<Value>, Box<Error>> {
+    let mut state: TimelineState = load_json(&state_path(path))?;
+    let mut archive: Vec<Value> = load_json(&archive_path(path))?;
+    let backend = Backend::from_env();
+
+    let (since_id, pages) = match (backfill, &state.newest_id) {
+        (true, _) => (None, backend.max_pages()),
+        (false, Some(id)) => (Some(id.as_str()), backend.max_pages()),
+        (false, None) => (None, 1)
+    };
+
+    let tweets = fetch_timeline(|since_id, max_id| backend.fetch_page(since_id, max_id), since_id, pages)?;
+    merge_tweets(&mut archive, tweets);
+
+    if let Some(newest) = archive.first() {
+        state.newest_id = newest["id_str"].as_str().map(|id| id.to_string());
+    }
+
+    std::fs::write(archive_path(path), serde_json::to_string(&archive)?)?;
+    std::fs::write(state_path(path), serde_json::to_string(&state)?)?;
+    Ok(archive)
+}
+
+/// Pages back through the entire timeline window into the archive, for the
+/// `backfill-twitter` command. Returns how many Tweets the archive holds afterwards.
+pub fn backfill() -> Result<usize, Box<Error>> {
+    let path = var("RYMC_ACTIVITY_PATH")?;
+    Ok(sync(&path, true)?.len())
+}
+
+/// Folds `tweets` (e.g, ones imported from a downloaded archive) into the raw archive
+/// in `path`, so they show up in the feed like anything fetched from the API would.
+pub fn archive_tweets(path: &str, tweets: Vec<Value>) -> Result<(), Box<Error>> {
+    let mut archive: Vec<Value> = load_json(&archive_path(path))?;
+    merge_tweets(&mut archive, tweets);
+    std::fs::write(archive_path(path), serde_json::to_string(&archive)?)?;
+    Ok(())
+}
+
+/// Renders the most recent Tweets in the raw archive in `path`, without calling out to Twitter.
+pub fn render_archive(path: &str) -> Result<Vec<Activity>, Box<Error>> {
+    let archive: Vec<Value> = load_json(&archive_path(path))?;
+
+    let tweets: Vec<Tweet> = archive.into_iter().filter_map(|tweet| {
+        match serde_json::from_value::<Tweet>(tweet) {
+            Ok(tweet) => Some(tweet),
+            Err(e) => { eprintln!("Error reading archived Tweet: {:?}", e); None }
+        }
+    }).collect();
+
+    Ok(recent_tweets_to_activities(tweets, FEED_TWEETS, &ReplyPolicy::from_env()))
+}
+
+/// Calls out to Twitter for anything new, then renders the most recent Tweets in the
+/// archive into a standard template that'll ultimately be rendered on the HTML side.
+pub fn get_and_transform_tweets_to_html() -> Result<Vec<Activity>, Box<Error>> {
+    let path = var("RYMC_ACTIVITY_PATH")?;
+
+    // If Twitter can't be reached (or the keys are gone), the Tweets archived so far still render.
+    if let Err(e) = sync(&path, false) {
+        eprintln!("Error syncing Tweets, rendering the archive as-is: {:?}", e);
+    }
+
+    render_archive(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tweet(json: Value) -> Tweet {
+        let mut tweet = serde_json::json!({
+            "id_str": "1",
+            "full_text": "",
+            "lang": "en",
+            "user": { "screen_name": "ryanmcgrath" },
+            "entities": { "hashtags": [], "user_mentions": [], "urls": [] },
+            "created_at": "Tue Jan 01 10:00:00 +0000 2019"
+        });
+
+        for (key, value) in json.as_object().unwrap() {
+            tweet[key] = value.clone();
+        }
+
+        serde_json::from_value(tweet).unwrap()
+    }
+
+    #[test]
+    fn decodes_entities_before_splicing() {
+        // Twitter counts `&amp;` as one character, so it has to be one by the time the
+        // indices get used.
+        let tweet = tweet(serde_json::json!({
+            "full_text": "Salt &amp; 🇯🇵 pepper &lt;3 https://t.co/abc",
+            "entities": {
+                "hashtags": [],
+                "user_mentions": [],
+                "urls": [{
+                    "url": "https://t.co/abc",
+                    "display_url": "rymc.io",
+                    "expanded_url": "https://rymc.io/",
+                    "indices": [20, 36]
+                }]
+            }
         }));
+
+        assert_eq!(patch_text(&tweet), "Salt & 🇯🇵 pepper \\<3 [rymc.io](https://rymc.io/)");
     }
 
-    Ok(activities)
+    fn reply(id: &str, to: &str, screen_name: &str, created_at: &str) -> Tweet {
+        tweet(serde_json::json!({
+            "id_str": id,
+            "full_text": format!("Tweet {}", id),
+            "in_reply_to_status_id_str": to,
+            "in_reply_to_screen_name": screen_name,
+            "created_at": created_at
+        }))
+    }
+
+    #[test]
+    fn threads_keep_the_first_tweet_as_their_identity() {
+        let tweets = vec![
+            reply("3", "2", "ryanmcgrath", "Tue Jan 01 12:00:00 +0000 2019"),
+            reply("2", "1", "ryanmcgrath", "Tue Jan 01 11:00:00 +0000 2019"),
+            tweet(serde_json::json!({ "id_str": "1", "full_text": "Tweet 1" }))
+        ];
+
+        let activities = tweets_to_activities_with(tweets, &ReplyPolicy::All);
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].content, "Tweet 1\n\nTweet 2\n\nTweet 3");
+        assert_eq!(activities[0].id, Some("1".into()));
+        assert_eq!(activities[0].datetime.url, "https://twitter.com/ryanmcgrath/status/1");
+        assert_eq!(activities[0].datetime.ts, NaiveDateTime::parse_from_str("2019-01-01 10:00", "%Y-%m-%d %H:%M").unwrap());
+    }
+
+    #[test]
+    fn threads_that_start_before_the_newest_tweets_keep_their_start() {
+        // Tweet 1 starts a thread, which 1000 picks back up after FEED_TWEETS other Tweets.
+        let mut tweets = vec![
+            tweet(serde_json::json!({ "id_str": "1", "full_text": "Tweet 1" })),
+            reply("1000", "1", "ryanmcgrath", "Wed Jan 02 10:00:00 +0000 2019")
+        ];
+
+        for id in 100..100 + FEED_TWEETS {
+            tweets.push(tweet(serde_json::json!({ "id_str": id.to_string(), "created_at": "Tue Jan 01 11:00:00 +0000 2019" })));
+        }
+
+        let activities = recent_tweets_to_activities(tweets, FEED_TWEETS, &ReplyPolicy::All);
+        assert_eq!(activities.len(), FEED_TWEETS);
+
+        let thread = activities.iter().find(|activity| activity.content.contains("Tweet 1000")).unwrap();
+        assert_eq!(thread.id, Some("1".into()));
+        assert_eq!(thread.content, "Tweet 1\n\nTweet 1000");
+
+        // Tweet 100 is the oldest of the rest, so that's the one that doesn't make it.
+        assert!(!activities.iter().any(|activity| activity.id == Some("100".into())));
+    }
+
+    #[test]
+    fn leaves_out_replies_by_policy() {
+        let tweets = || vec![
+            reply("3", "100", "someone", "Tue Jan 01 12:00:00 +0000 2019"),
+            reply("2", "1", "ryanmcgrath", "Tue Jan 01 11:00:00 +0000 2019"),
+            tweet(serde_json::json!({ "id_str": "1", "full_text": "Tweet 1" }))
+        ];
+
+        assert_eq!(tweets_to_activities_with(tweets(), &ReplyPolicy::All).len(), 2);
+
+        let threads = tweets_to_activities_with(tweets(), &ReplyPolicy::SelfThreads);
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].content, "Tweet 1\n\nTweet 2");
+
+        let none = tweets_to_activities_with(tweets(), &ReplyPolicy::Exclude);
+        assert_eq!(none.len(), 1);
+        assert_eq!(none[0].content, "Tweet 1");
+    }
+
+    #[test]
+    fn links_gifs_to_the_file_they_play() {
+        let tweet = tweet(serde_json::json!({
+            "full_text": "Look https://t.co/gif",
+            "display_text_range": [0, 4],
+            "extended_entities": {
+                "media": [{
+                    "id_str": "10",
+                    "url": "https://t.co/gif",
+                    "display_url": "pic.twitter.com/gif",
+                    "expanded_url": "https://twitter.com/ryanmcgrath/status/1/photo/1",
+                    "type": "animated_gif",
+                    "indices": [5, 21],
+                    "media_url_https": "https://pbs.twimg.com/tweet_video_thumb/abc.jpg",
+                    "ext_alt_text": "A dog",
+                    "video_info": {
+                        "variants": [{ "bitrate": 0, "content_type": "video/mp4", "url": "https://video.twimg.com/tweet_video/abc.mp4" }]
+                    }
+                }]
+            }
+        }));
+
+        assert_eq!(
+            patch_text(&tweet),
+            "Look\n\n[![A dog](https://pbs.twimg.com/tweet_video_thumb/abc.jpg)](https://video.twimg.com/tweet_video/abc.mp4 \"Play this gif\")"
+        );
+    }
+
+    #[test]
+    fn quotes_every_line_of_a_quoted_tweet() {
+        let tweet = tweet(serde_json::json!({
+            "full_text": "Agreed",
+            "quoted_status": {
+                "id_str": "50",
+                "full_text": "First line\n\nSecond line",
+                "lang": "en",
+                "user": { "screen_name": "some_one" },
+                "entities": { "hashtags": [], "user_mentions": [], "urls": [] },
+                "created_at": "Mon Dec 31 10:00:00 +0000 2018"
+            }
+        }));
+
+        assert_eq!(
+            patch_text(&tweet),
+            "Agreed\n\n\
+             > [@some\\_one](https://twitter.com/some_one/status/50 \"View this Tweet on Twitter\"): First line\n\
+             > \n\
+             > Second line"
+        );
+    }
+
+    #[test]
+    fn says_who_a_reply_is_to() {
+        let tweets = vec![
+            reply("2", "100", "some_one", "Tue Jan 01 11:00:00 +0000 2019"),
+            reply("3", "2", "ryanmcgrath", "Tue Jan 01 12:00:00 +0000 2019")
+        ];
+
+        // Only the start of the thread says who it's replying to.
+        let activities = tweets_to_activities_with(tweets, &ReplyPolicy::All);
+        assert_eq!(activities.len(), 1);
+        assert_eq!(
+            activities[0].content,
+            "Replying to [@some\\_one](https://twitter.com/some_one/status/100 \"View the Tweet this replies to on Twitter\")\n\n\
+             Tweet 2\n\nTweet 3"
+        );
+
+        assert_eq!(reply_context(&tweet(serde_json::json!({ "full_text": "Hi" }))), None);
+    }
+
+    fn page(ids: &[u64]) -> Vec<Value> {
+        ids.iter().map(|id| serde_json::json!({ "id_str": id.to_string() })).collect()
+    }
+
+    fn ids(tweets: &[Value]) -> Vec<u64> {
+        tweets.iter().map(tweet_id).collect()
+    }
+
+    #[test]
+    fn pages_back_until_an_empty_page() {
+        let mut pages = vec![page(&[30, 29, 28]), page(&[27, 25]), page(&[]), page(&[10])].into_iter();
+        let mut calls: Vec<(Option<String>, Option<u64>)> = vec![];
+
+        let tweets = fetch_timeline(|since_id, max_id| {
+            calls.push((since_id.map(|id| id.to_string()), max_id));
+            Ok(pages.next().unwrap())
+        }, Some("20"), 10).unwrap();
+
+        assert_eq!(ids(&tweets), vec![30, 29, 28, 27, 25]);
+        assert_eq!(calls, vec![
+            (Some("20".into()), None),
+            (Some("20".into()), Some(27)),
+            (Some("20".into()), Some(24))
+        ]);
+    }
+
+    #[test]
+    fn stops_after_the_page_limit() {
+        let mut calls = 0;
+
+        let tweets = fetch_timeline(|_, max_id| {
+            calls += 1;
+            let newest = max_id.unwrap_or(100);
+            Ok(page(&[newest, newest - 1]))
+        }, None, 2).unwrap();
+
+        assert_eq!(calls, 2);
+        assert_eq!(ids(&tweets), vec![100, 99, 98, 97]);
+    }
+
+    #[test]
+    fn merges_overlapping_pages_into_the_archive() {
+        let mut archive = page(&[5, 4, 3]);
+        archive[1]["full_text"] = "Archived".into();
+
+        let mut fetched = page(&[6, 5, 4]);
+        fetched.extend(page(&[4, 2]));
+        merge_tweets(&mut archive, fetched);
+
+        assert_eq!(ids(&archive), vec![6, 5, 4, 3, 2]);
+        // What's already archived stays as it was.
+        assert_eq!(archive[2]["full_text"], "Archived");
+    }
+
+    fn rendered(tweets: Vec<Tweet>) -> Vec<(Option<String>, Value)> {
+        tweets_to_activities_with(tweets, &ReplyPolicy::All).into_iter().map(|activity| {
+            (activity.id.clone(), serde_json::to_value(&activity).unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn v1_and_v2_render_the_same() {
+        let v1: Vec<Tweet> = serde_json::from_str(include_str!("../fixtures/twitter/v1-timeline.json")).unwrap();
+        let v2: Vec<Tweet> = twitter_v2::parse_page(include_str!("../fixtures/twitter/v2-timeline.json")).unwrap()
+            .into_iter().map(|tweet| serde_json::from_value(tweet).unwrap()).collect();
+
+        let v1 = rendered(v1);
+        // The self-reply folds into the Tweet it replies to.
+        assert_eq!(v1.len(), 2);
+        assert_eq!(v1, rendered(v2));
+    }
+
+    #[test]
+    fn skips_v2_tweets_without_a_readable_timestamp() {
+        let tweets = twitter_v2::parse_page(include_str!("../fixtures/twitter/v2-timeline.json")).unwrap();
+        let ids: Vec<&str> = tweets.iter().filter_map(|tweet| tweet["id_str"].as_str()).collect();
+        assert_eq!(ids, vec!["102", "101", "100"]);
+    }
 }
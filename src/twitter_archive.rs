@@ -0,0 +1,344 @@
+//! twitter_archive.rs
+//!
+//! Imports the archive Twitter lets you download ("Download an archive of your
+//! data"), so the history stops depending on the API at all. Point it at either
+//! the zip as-is or the folder it unzips to; Tweets live in `data/tweets.js` and
+//! their media in `data/tweets_media`.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::collections::HashSet;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::archive;
+use crate::twitter::{self, Tweet, tweets_to_activities};
+
+lazy_static! {
+    // Older archives call it `tweet.js`, and bigger ones split it into parts.
+    static ref TWEETS_FILE_REGEX: Regex = Regex::new(r"^data/tweets?(-part\d+)?\.js$").unwrap();
+}
+
+/// An entry in `tweets.js`.
+#[derive(Deserialize, Debug)]
+struct Entry {
+    tweet: Value
+}
+
+/// Where a file sits relative to the archive's root (which zips sometimes nest inside a
+/// folder of their own): everything from its `data` directory on, e.g `data/tweets.js`.
+/// Anything that could point somewhere else entirely - an absolute path, or one with `..`
+/// in it - gets `None`, as does anything outside of `data`.
+fn archive_name(path: &Path) -> Option<String> {
+    let mut parts: Vec<&str> = vec![];
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {},
+            _ => return None
+        }
+    }
+
+    let root = parts.iter().position(|part| *part == "data")?;
+    Some(parts[root..].join("/"))
+}
+
+/// What's been pulled out of the archive so far: the raw Tweets, and the names of the
+/// media files copied into `media_path`.
+struct Import {
+    raw: Vec<Value>,
+    copied: HashSet<String>,
+    media_path: PathBuf
+}
+
+impl Import {
+    /// Takes in one file from the archive. Media gets streamed straight to disk, so it never
+    /// all has to fit in memory at once; only the Tweets themselves get read in.
+    fn add(&mut self, name: &str, contents: &mut Read) -> Result<(), Box<Error>> {
+        if TWEETS_FILE_REGEX.is_match(name) {
+            let mut tweets: Vec<u8> = vec![];
+            contents.read_to_end(&mut tweets)?;
+            self.raw.append(&mut parse_tweets(&tweets)?);
+            return Ok(());
+        }
+
+        // Only files directly inside `tweets_media`, which `archive_name` has already made
+        // sure are plain names.
+        if let Some(file) = name.strip_prefix("data/tweets_media/") {
+            if !file.is_empty() && !file.contains('/') {
+                std::io::copy(contents, &mut std::fs::File::create(self.media_path.join(file))?)?;
+                self.copied.insert(file.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads an unzipped archive. Not every archive has media, so `data/tweets_media` can
+    /// be missing.
+    fn add_dir(&mut self, source: &Path) -> Result<(), Box<Error>> {
+        for directory in &["data", "data/tweets_media"] {
+            let directory = source.join(directory);
+            if !directory.is_dir() {
+                continue;
+            }
+
+            for entry in std::fs::read_dir(&directory)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+
+                if let Some(name) = entry.path().strip_prefix(source).ok().and_then(archive_name) {
+                    self.add(&name, &mut std::fs::File::open(entry.path())?)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the archive as downloaded. Entries get their names checked before anything's
+    /// written, so a zip can't put files anywhere outside of `media_path`.
+    fn add_zip(&mut self, source: &Path) -> Result<(), Box<Error>> {
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(source)?)?;
+
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if !file.is_file() {
+                continue;
+            }
+
+            let name = match file.enclosed_name() {
+                Some(path) => archive_name(path),
+                None => {
+                    eprintln!("Skipping {} in the Twitter archive, as it points outside of it.", file.name());
+                    continue;
+                }
+            };
+
+            if let Some(name) = name {
+                self.add(&name, &mut file)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `tweets.js` is JSON with a bit of JavaScript in front of it
+/// (`window.YTD.tweets.part0 = [...]`).
+fn parse_tweets(contents: &[u8]) -> Result<Vec<Value>, Box<Error>> {
+    let contents = String::from_utf8_lossy(contents);
+    let json = match contents.find('=') {
+        Some(index) => &contents[index + 1..],
+        None => &contents[..]
+    };
+
+    let entries: Vec<Entry> = serde_json::from_str(json)?;
+    Ok(entries.into_iter().map(|entry| entry.tweet).collect())
+}
+
+/// Media in the archive is named `<tweet id>-<file name>`, where the file name is whatever
+/// it was on Twitter's servers.
+fn local_media_url(tweet_id: &str, url: &str, copied: &HashSet<String>, base_url: &str) -> Option<String> {
+    let file = url.split('?').next()?.rsplit('/').next()?;
+    let name = format!("{}-{}", tweet_id, file);
+
+    match copied.contains(&name) {
+        true => Some(format!("{}/{}", base_url, name)),
+        false => None
+    }
+}
+
+/// Points a Tweet's media at our copies, wherever we have them.
+fn relink_media(tweet: &mut Value, copied: &HashSet<String>, base_url: &str) {
+    let id = tweet["id_str"].as_str().unwrap_or_default().to_string();
+    let media = match tweet.pointer_mut("/extended_entities/media").and_then(|m| m.as_array_mut()) {
+        Some(media) => media,
+        None => return
+    };
+
+    for item in media.iter_mut() {
+        if let Some(url) = item["media_url_https"].as_str().and_then(|u| local_media_url(&id, u, copied, base_url)) {
+            item["media_url_https"] = Value::String(url);
+        }
+
+        if let Some(variants) = item.pointer_mut("/video_info/variants").and_then(|v| v.as_array_mut()) {
+            for variant in variants.iter_mut() {
+                if let Some(url) = variant["url"].as_str().and_then(|u| local_media_url(&id, u, copied, base_url)) {
+                    variant["url"] = Value::String(url);
+                }
+            }
+        }
+    }
+}
+
+/// Imports the archive at `source` into the activity archive in `path`, copying its media
+/// into `path/twitter-media`. Returns how many activities that came out to.
+pub fn import(source: &Path, path: &str) -> Result<usize, Box<Error>> {
+    let base_url = var("RYMC_TWITTER_MEDIA_URL").unwrap_or_else(|_| "twitter-media".into());
+    let media_path = Path::new(path).join("twitter-media");
+    std::fs::create_dir_all(&media_path)?;
+
+    let mut import = Import { raw: vec![], copied: HashSet::new(), media_path: media_path };
+    match source.is_dir() {
+        true => import.add_dir(source)?,
+        false => import.add_zip(source)?
+    }
+
+    let copied = import.copied;
+
+    // Archived Tweets don't say who wrote them; it's always the account owner.
+    let raw: Vec<Value> = import.raw.into_iter().map(|mut tweet| {
+        relink_media(&mut tweet, &copied, &base_url);
+        if tweet.get("user").is_none() {
+            tweet["user"] = serde_json::json!({ "screen_name": "ryanmcgrath" });
+        }
+
+        tweet
+    }).collect();
+
+    let tweets: Vec<Tweet> = raw.iter().cloned().filter_map(|tweet| {
+        match serde_json::from_value::<Tweet>(tweet) {
+            Ok(tweet) => Some(tweet),
+            Err(e) => { eprintln!("Error reading archived Tweet: {:?}", e); None }
+        }
+    }).collect();
+
+    let activities = tweets_to_activities(tweets);
+    let mut history = archive::load(path)?;
+    archive::merge(&mut history, activities.iter().map(archive::ArchivedActivity::from));
+    archive::save(path, &history)?;
+
+    // The feed renders from the raw Tweet archive, so they go there too.
+    twitter::archive_tweets(path, raw)?;
+
+    Ok(activities.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const TWEETS: &str = r#"window.YTD.tweets.part0 = [{
+        "tweet": {
+            "id_str": "1",
+            "full_text": "Look https://t.co/abc",
+            "display_text_range": ["0", "4"],
+            "lang": "en",
+            "entities": {
+                "hashtags": [],
+                "user_mentions": [],
+                "urls": [],
+                "media": [{
+                    "id_str": "10", "url": "https://t.co/abc", "display_url": "pic.twitter.com/abc",
+                    "expanded_url": "https://twitter.com/ryanmcgrath/status/1/photo/1", "indices": ["5", "21"]
+                }]
+            },
+            "extended_entities": {
+                "media": [{
+                    "id_str": "10", "url": "https://t.co/abc", "display_url": "pic.twitter.com/abc",
+                    "expanded_url": "https://twitter.com/ryanmcgrath/status/1/photo/1", "indices": ["5", "21"],
+                    "type": "photo", "media_url_https": "https://pbs.twimg.com/media/abc.jpg"
+                }]
+            },
+            "created_at": "Tue Jan 01 10:00:00 +0000 2019"
+        }
+    }]"#;
+
+    fn zip(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored)).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+
+        zip.finish().unwrap();
+    }
+
+    fn media(path: &Path) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(path.join("twitter-media")).unwrap().map(|entry| {
+            entry.unwrap().file_name().to_string_lossy().into_owned()
+        }).collect();
+
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn finds_the_data_directory_by_component() {
+        assert_eq!(archive_name(Path::new("data/tweets.js")), Some("data/tweets.js".into()));
+        assert_eq!(archive_name(Path::new("twitter-2019/data/tweets_media/1-abc.jpg")), Some("data/tweets_media/1-abc.jpg".into()));
+        assert_eq!(archive_name(Path::new("metadata/tweets.js")), None);
+        assert_eq!(archive_name(Path::new("data/tweets_media/../../evil.jpg")), None);
+        assert_eq!(archive_name(Path::new("/data/tweets.js")), None);
+    }
+
+    #[test]
+    fn imports_a_zip_without_writing_outside_of_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        std::fs::create_dir(&output).unwrap();
+
+        let source = dir.path().join("archive.zip");
+        zip(&source, &[
+            ("twitter-2019/data/tweets.js", TWEETS),
+            ("twitter-2019/data/tweets_media/1-abc.jpg", "jpeg"),
+            ("twitter-2019/metadata/tweets_media/2-decoy.jpg", "decoy"),
+            ("../evil.jpg", "evil"),
+            ("/tmp/evil.jpg", "evil"),
+            ("data/tweets_media/../../../evil.jpg", "evil")
+        ]);
+
+        assert_eq!(import(&source, output.to_str().unwrap()).unwrap(), 1);
+        assert_eq!(media(&output), vec!["1-abc.jpg"]);
+        assert_eq!(std::fs::read_to_string(output.join("twitter-media/1-abc.jpg")).unwrap(), "jpeg");
+        assert!(!dir.path().join("evil.jpg").exists());
+
+        let history = archive::load(output.to_str().unwrap()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "Look\n\n![](twitter-media/1-abc.jpg)");
+    }
+
+    #[test]
+    fn imports_a_folder_without_any_media() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        std::fs::create_dir_all(dir.path().join("archive/data")).unwrap();
+        std::fs::create_dir(&output).unwrap();
+        std::fs::write(dir.path().join("archive/data/tweets.js"), TWEETS).unwrap();
+
+        assert_eq!(import(&dir.path().join("archive"), output.to_str().unwrap()).unwrap(), 1);
+        assert!(media(&output).is_empty());
+
+        // Nothing got copied, so the media stays where Twitter had it.
+        let history = archive::load(output.to_str().unwrap()).unwrap();
+        assert_eq!(history[0].content, "Look\n\n![](https://pbs.twimg.com/media/abc.jpg)");
+    }
+
+    #[test]
+    fn renders_imported_tweets_without_the_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        std::fs::create_dir(&output).unwrap();
+
+        let source = dir.path().join("archive.zip");
+        zip(&source, &[("data/tweets.js", TWEETS), ("data/tweets_media/1-abc.jpg", "jpeg")]);
+        import(&source, output.to_str().unwrap()).unwrap();
+
+        // Importing twice doesn't double anything up.
+        import(&source, output.to_str().unwrap()).unwrap();
+
+        let activities = twitter::render_archive(output.to_str().unwrap()).unwrap();
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].content, "Look\n\n![](twitter-media/1-abc.jpg)");
+    }
+}
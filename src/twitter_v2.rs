@@ -0,0 +1,298 @@
+//! twitter_v2.rs
+//!
+//! The v2 API backend for Twitter (`users/:id/tweets`, app-only bearer auth). v2
+//! splits a Tweet's media, users and referenced Tweets out into `includes`, so
+//! this stitches them back together into the v1.1 shape - everything past the
+//! fetch (the archive, threads, rendering) then works the same for both.
+//!
+//! @author Ryan McGrath <ryan@rymc.io>
+//! @copyright RYMC 2019
+
+use std::env::var;
+use std::error::Error;
+use std::collections::HashMap;
+use serde_json::{Value, json};
+use chrono::NaiveDateTime;
+
+use crate::escape;
+
+pub const PAGE_SIZE: usize = 100;
+
+const API_URL: &'static str = "https://api.twitter.com/2";
+
+const EXPANSIONS: &'static str = "attachments.media_keys,referenced_tweets.id,referenced_tweets.id.author_id,referenced_tweets.id.attachments.media_keys,author_id,in_reply_to_user_id";
+const TWEET_FIELDS: &'static str = "created_at,lang,entities,attachments,referenced_tweets,in_reply_to_user_id,author_id";
+const MEDIA_FIELDS: &'static str = "type,url,preview_image_url,alt_text,variants";
+
+#[derive(Deserialize, Debug)]
+struct Includes {
+    #[serde(default)]
+    media: Vec<Value>,
+
+    #[serde(default)]
+    tweets: Vec<Value>,
+
+    #[serde(default)]
+    users: Vec<Value>
+}
+
+#[derive(Deserialize, Debug)]
+struct Response {
+    data: Option<Vec<Value>>,
+    includes: Option<Includes>,
+
+    #[serde(default)]
+    errors: Vec<Value>
+}
+
+/// `includes`, keyed by ID (or `media_key`) so conversion doesn't have to go digging.
+struct Lookup {
+    media: HashMap<String, Value>,
+    tweets: HashMap<String, Value>,
+    users: HashMap<String, String>
+}
+
+impl Lookup {
+    fn new(includes: Option<Includes>) -> Self {
+        let includes = includes.unwrap_or(Includes { media: vec![], tweets: vec![], users: vec![] });
+        let key = |v: &Value, field: &str| v[field].as_str().unwrap_or_default().to_string();
+
+        Lookup {
+            media: includes.media.into_iter().map(|m| (key(&m, "media_key"), m)).collect(),
+            tweets: includes.tweets.into_iter().map(|t| (key(&t, "id"), t)).collect(),
+            users: includes.users.iter().map(|u| (key(u, "id"), key(u, "username"))).collect()
+        }
+    }
+
+    fn username(&self, id: &Value) -> Option<String> {
+        id.as_str().and_then(|id| self.users.get(id)).cloned()
+    }
+}
+
+fn indices(entity: &Value) -> Value {
+    json!([entity["start"].as_u64().unwrap_or(0), entity["end"].as_u64().unwrap_or(0)])
+}
+
+fn referenced(tweet: &Value, kind: &str) -> Option<String> {
+    tweet["referenced_tweets"].as_array()?.iter().find(|r| r["type"] == kind)?["id"].as_str().map(|id| id.to_string())
+}
+
+/// v1.1 leaves a reply's leading @mentions out of `display_text_range`; v2 doesn't say,
+/// so work it out from where the mentions are. Like the entity indices, this counts
+/// `&amp;` and friends as the one character they stand for.
+fn display_text_range(tweet: &Value, text: &str) -> Value {
+    let chars: Vec<char> = escape::decode_html_entities(text).chars().collect();
+    let mut start = 0;
+
+    if referenced(tweet, "replied_to").is_some() {
+        let mut mentions: Vec<(usize, usize)> = tweet["entities"]["mentions"].as_array().map(|mentions| {
+            mentions.iter().map(|m| (m["start"].as_u64().unwrap_or(0) as usize, m["end"].as_u64().unwrap_or(0) as usize)).collect()
+        }).unwrap_or_default();
+
+        mentions.sort();
+        for (mention_start, mention_end) in mentions {
+            while start < chars.len() && chars[start].is_whitespace() {
+                start += 1;
+            }
+
+            if mention_start != start {
+                break;
+            }
+
+            start = mention_end;
+        }
+
+        while start < chars.len() && chars[start].is_whitespace() {
+            start += 1;
+        }
+    }
+
+    json!([start, chars.len()])
+}
+
+fn convert_media(media: &Value, entity: Option<&Value>) -> Value {
+    let variants: Vec<Value> = media["variants"].as_array().map(|variants| variants.iter().map(|variant| json!({
+        "bitrate": variant["bit_rate"],
+        "content_type": variant["content_type"],
+        "url": variant["url"]
+    })).collect()).unwrap_or_default();
+
+    let image = match media["url"].as_str() {
+        Some(url) => url,
+        None => media["preview_image_url"].as_str().unwrap_or_default()
+    };
+
+    let link = |field: &str| entity.and_then(|e| e[field].as_str()).unwrap_or_default().to_string();
+
+    json!({
+        "id_str": media["media_key"],
+        "url": link("url"),
+        "display_url": link("display_url"),
+        "expanded_url": link("expanded_url"),
+        "indices": entity.map(indices).unwrap_or(json!([0, 0])),
+        "type": media["type"],
+        "media_url_https": image,
+        "ext_alt_text": media["alt_text"],
+        "video_info": match variants.is_empty() {
+            true => Value::Null,
+            false => json!({ "variants": variants })
+        }
+    })
+}
+
+/// Reshapes a v2 Tweet into what v1.1 would have handed back. A Tweet without a usable
+/// `created_at` can't be placed anywhere, so that's an error.
+fn convert(tweet: &Value, lookup: &Lookup) -> Result<Value, Box<Error>> {
+    let text = tweet["text"].as_str().unwrap_or_default();
+    let entities = &tweet["entities"];
+    let list = |field: &str| entities[field].as_array().cloned().unwrap_or_default();
+
+    // Media links show up as plain URL entities, tagged with the media they point to.
+    let (media_links, urls): (Vec<Value>, Vec<Value>) = list("urls").into_iter().partition(|url| url["media_key"].is_string());
+
+    let media: Vec<Value> = tweet["attachments"]["media_keys"].as_array().map(|keys| keys.iter().filter_map(|key| {
+        let link = media_links.iter().find(|link| link["media_key"] == *key);
+        lookup.media.get(key.as_str()?).map(|media| convert_media(media, link))
+    }).collect()).unwrap_or_default();
+
+    let created_at = match tweet["created_at"].as_str().map(|ts| NaiveDateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S%.fZ")) {
+        Some(Ok(ts)) => ts.format("%a %b %d %H:%M:%S +0000 %Y").to_string(),
+        _ => return Err(format!("Tweet {} has no readable created_at ({})", tweet["id"], tweet["created_at"]).into())
+    };
+
+    // A retweeted or quoted Tweet we can't read just gets left out.
+    let nested = |kind: &str| referenced(tweet, kind).and_then(|id| lookup.tweets.get(&id)).and_then(|t| {
+        convert(t, lookup).map_err(|e| eprintln!("Leaving out the {} Tweet in {}: {}", kind, tweet["id"], e)).ok()
+    });
+
+    Ok(json!({
+        "id_str": tweet["id"],
+        "full_text": text,
+        "display_text_range": display_text_range(tweet, text),
+        "lang": tweet["lang"].as_str().unwrap_or("und"),
+        "user": { "screen_name": lookup.username(&tweet["author_id"]).unwrap_or_else(|| "ryanmcgrath".into()) },
+        "entities": {
+            "hashtags": list("hashtags").iter().map(|h| json!({ "text": h["tag"], "indices": indices(h) })).collect::<Vec<Value>>(),
+            "user_mentions": list("mentions").iter().map(|m| json!({
+                "screen_name": m["username"], "id_str": m["id"], "indices": indices(m)
+            })).collect::<Vec<Value>>(),
+            "urls": urls.iter().map(|u| json!({
+                "url": u["url"], "display_url": u["display_url"], "expanded_url": u["expanded_url"], "indices": indices(u)
+            })).collect::<Vec<Value>>(),
+            "media": media_links.iter().filter_map(|link| {
+                lookup.media.get(link["media_key"].as_str()?).map(|media| convert_media(media, Some(link)))
+            }).collect::<Vec<Value>>()
+        },
+        "extended_entities": match media.is_empty() {
+            true => Value::Null,
+            false => json!({ "media": media })
+        },
+        "retweeted_status": nested("retweeted"),
+        "quoted_status": nested("quoted"),
+        "in_reply_to_status_id_str": referenced(tweet, "replied_to"),
+        "in_reply_to_screen_name": lookup.username(&tweet["in_reply_to_user_id"]),
+        "created_at": created_at
+    }))
+}
+
+/// Converts a page of v2 Tweets, skipping (and saying so) any that won't convert. A page
+/// with nothing but `errors` (as opposed to no Tweets, or a few bits of `includes` that
+/// couldn't be found) is an error.
+pub fn parse_page(body: &str) -> Result<Vec<Value>, Box<Error>> {
+    let response: Response = serde_json::from_str(body)?;
+    let data = match (response.data, response.errors.is_empty()) {
+        (Some(data), _) => data,
+        (None, true) => vec![],
+        (None, false) => return Err(format!("Twitter API returned errors: {}", Value::Array(response.errors)).into())
+    };
+
+    let lookup = Lookup::new(response.includes);
+
+    Ok(data.iter().filter_map(|tweet| match convert(tweet, &lookup) {
+        Ok(tweet) => Some(tweet),
+        Err(e) => { eprintln!("Skipping a Tweet: {}", e); None }
+    }).collect())
+}
+
+/// One page of the timeline, as v1.1-shaped Tweets. `since_id` is exclusive and
+/// `max_id` inclusive, same as v1.1.
+pub fn fetch_page(since_id: Option<&str>, max_id: Option<u64>) -> Result<Vec<Value>, Box<Error>> {
+    let user_id = var("RYMC_TWITTER_USER_ID")?;
+    let bearer_token = var("RYMC_TWITTER_BEARER_TOKEN")?;
+    fetch(API_URL, &user_id, &bearer_token, since_id, max_id)
+}
+
+/// Asks `api_url` (normally `https://api.twitter.com/2`) for a page. Anything other than
+/// a success (a bad token, being rate limited) is an error, rather than an empty page.
+fn fetch(api_url: &str, user_id: &str, bearer_token: &str, since_id: Option<&str>, max_id: Option<u64>) -> Result<Vec<Value>, Box<Error>> {
+    let mut endpoint = format!(
+        "{}/users/{}/tweets?max_results={}&expansions={}&tweet.fields={}&media.fields={}&user.fields=username",
+        api_url, user_id, PAGE_SIZE, EXPANSIONS, TWEET_FIELDS, MEDIA_FIELDS
+    );
+
+    if let Some(since_id) = since_id {
+        endpoint.push_str(&format!("&since_id={}", since_id));
+    }
+
+    // v2's `until_id` is exclusive.
+    if let Some(max_id) = max_id {
+        endpoint.push_str(&format!("&until_id={}", max_id + 1));
+    }
+
+    let client = reqwest::Client::new();
+    let mut response = client.get(&endpoint).bearer_auth(bearer_token).send()?;
+    let body = response.text()?;
+
+    if !response.status().is_success() {
+        return Err(format!("Twitter API returned {}: {}", response.status(), body).into());
+    }
+
+    parse_page(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{paths, serve_with, status};
+
+    #[test]
+    fn fails_on_an_error_status() {
+        let (url, requests) = serve_with(|_| vec![
+            status(401, r#"{ "title": "Unauthorized", "type": "about:blank", "status": 401, "detail": "Unauthorized" }"#),
+            status(429, r#"{ "title": "Too Many Requests", "detail": "Too Many Requests", "type": "about:blank", "status": 429 }"#)
+        ]);
+
+        let error = fetch(&url, "12", "token", Some("100"), None).unwrap_err();
+        assert!(error.to_string().contains("401"), "{}", error);
+
+        let error = fetch(&url, "12", "token", None, Some(200)).unwrap_err();
+        assert!(error.to_string().contains("429"), "{}", error);
+
+        let paths = paths(&requests);
+        assert!(paths[0].starts_with("/users/12/tweets?max_results=100&"));
+        assert!(paths[0].ends_with("&since_id=100"));
+        assert!(paths[1].ends_with("&until_id=201"));
+    }
+
+    #[test]
+    fn fails_on_a_page_of_only_errors() {
+        let (url, _) = serve_with(|_| vec![
+            r#"{ "errors": [{ "title": "Not Found Error", "detail": "Could not find user with id: [12]." }] }"#.to_string()
+        ]);
+
+        let error = fetch(&url, "12", "token", None, None).unwrap_err();
+        assert!(error.to_string().contains("Could not find user"), "{}", error);
+    }
+
+    #[test]
+    fn reads_an_empty_page_as_no_tweets() {
+        assert!(parse_page(r#"{ "meta": { "result_count": 0 } }"#).unwrap().is_empty());
+
+        // Errors alongside data are just bits of `includes` that couldn't be found.
+        let tweets = parse_page(r#"{
+            "data": [{ "id": "1", "text": "Hi", "created_at": "2019-01-01T10:00:00.000Z" }],
+            "errors": [{ "title": "Not Found Error", "resource_type": "tweet" }]
+        }"#).unwrap();
+        assert_eq!(tweets.len(), 1);
+    }
+}